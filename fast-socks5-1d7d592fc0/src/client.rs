@@ -81,6 +81,19 @@ where
         Ok(stream)
     }
 
+    /// Sends a BIND request over a stream already created, see `Socks5Stream::bind`.
+    pub async fn bind_stream(
+        socket: S,
+        target_addr: TargetAddr,
+        auth: Option<AuthenticationMethod>,
+        config: Config,
+    ) -> Result<Socks5Listener<S>> {
+        let mut stream = Self::use_stream(socket, auth, config).await?;
+        let bind_addr = stream.request(Socks5Command::TCPBind, target_addr).await?;
+
+        Ok(Socks5Listener { stream, bind_addr })
+    }
+
     pub async fn request(
         &mut self,
         cmd: Socks5Command,
@@ -379,6 +392,32 @@ where
     }
 }
 
+/// A SOCKS5 proxy listening for a connection on behalf of the client, after
+/// the first reply of a BIND request.
+#[derive(Debug)]
+pub struct Socks5Listener<S: AsyncRead + AsyncWrite + Unpin> {
+    stream: Socks5Stream<S>,
+    bind_addr: TargetAddr,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Socks5Listener<S> {
+    /// Address the proxy listens on, to be handed over to the remote host.
+    pub fn bind_addr(&self) -> &TargetAddr {
+        &self.bind_addr
+    }
+
+    /// Waits for the remote host to connect, returns the stream to it along
+    /// with its address, as given by the second reply of the proxy.
+    pub async fn accept(mut self) -> Result<(Socks5Stream<S>, TargetAddr)> {
+        let peer_addr = self.stream.read_request_reply().await?;
+        Ok((self.stream, peer_addr))
+    }
+
+    pub fn get_socket_ref(&self) -> &S {
+        self.stream.get_socket_ref()
+    }
+}
+
 /// A SOCKS5 UDP client.
 #[derive(Debug)]
 pub struct Socks5Datagram<S: AsyncRead + AsyncWrite + Unpin> {
@@ -572,6 +611,64 @@ impl Socks5Stream<TcpStream> {
         .await
     }
 
+    /// Asks a SOCKS5 proxy to listen for a connection coming from
+    /// `target_addr:target_port`, as active-mode FTP does. The proxy may
+    /// accept it from anywhere when given `0.0.0.0:0`.
+    pub async fn bind<T>(
+        socks_server: T,
+        target_addr: String,
+        target_port: u16,
+        config: Config,
+    ) -> Result<Socks5Listener<TcpStream>>
+    where
+        T: ToSocketAddrs,
+    {
+        Self::bind_raw(socks_server, target_addr, target_port, None, config).await
+    }
+
+    /// Same as `bind`, with credentials
+    pub async fn bind_with_password<T>(
+        socks_server: T,
+        target_addr: String,
+        target_port: u16,
+        username: String,
+        password: String,
+        config: Config,
+    ) -> Result<Socks5Listener<TcpStream>>
+    where
+        T: ToSocketAddrs,
+    {
+        let auth = AuthenticationMethod::Password { username, password };
+
+        Self::bind_raw(socks_server, target_addr, target_port, Some(auth), config).await
+    }
+
+    async fn bind_raw<T>(
+        socks_server: T,
+        target_addr: String,
+        target_port: u16,
+        auth: Option<AuthenticationMethod>,
+        config: Config,
+    ) -> Result<Socks5Listener<TcpStream>>
+    where
+        T: ToSocketAddrs,
+    {
+        let socket = TcpStream::connect(
+            socks_server
+                .to_socket_addrs()?
+                .next()
+                .context("unreachable")?,
+        )
+        .await?;
+        info!("Connected @ {}", &socket.peer_addr()?);
+
+        let target_addr = (target_addr.as_str(), target_port)
+            .to_target_addr()
+            .context("Can't convert address to TargetAddr format")?;
+
+        Self::bind_stream(socket, target_addr, auth, config).await
+    }
+
     /// Process clients SOCKS requests
     /// This is the entry point where a whole request is processed.
     pub async fn connect_raw<T>(
@@ -295,7 +295,9 @@ mod test {
         sync::Arc,
     };
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::time::Duration;
     use tokio::sync::oneshot;
+    use tokio::time::timeout;
     use tokio_test::block_on;
 
     fn init() {
@@ -309,6 +311,7 @@ mod test {
     ) -> Result<()> {
         let mut config = server::Config::default();
         config.set_udp_support(true);
+        config.set_bind_support(true);
         match auth {
             None => {}
             Some(up) => {
@@ -402,6 +405,70 @@ mod test {
         });
     }
 
+    #[test]
+    fn mock_bind_no_auth() {
+        init();
+        block_on(async {
+            let (tx, rx) = oneshot::channel();
+            tokio::spawn(setup_socks_server("[::1]:0", None, tx));
+
+            let listener = client::Socks5Stream::bind(
+                rx.await.unwrap(),
+                "::".to_owned(),
+                0,
+                client::Config::default(),
+            )
+            .await
+            .unwrap();
+            let bind_addr = listener.bind_addr().to_socket_addrs().unwrap().next().unwrap();
+
+            // The remote host connects to the address handed over by the proxy.
+            let mut remote = TcpStream::connect(bind_addr).await.unwrap();
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            assert_eq!(
+                peer_addr.to_socket_addrs().unwrap().next().unwrap(),
+                remote.local_addr().unwrap()
+            );
+
+            let mut socket = stream.get_socket();
+            remote.write_all(b"hello world!").await.unwrap();
+            let mut buf = [0; 12];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello world!");
+
+            socket.write_all(b"hello remote").await.unwrap();
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello remote");
+        });
+    }
+
+    #[test]
+    fn mock_bind_refuses_unexpected_peer() {
+        init();
+        block_on(async {
+            let (tx, rx) = oneshot::channel();
+            tokio::spawn(setup_socks_server("[::1]:0", None, tx));
+
+            // The client only expects a connection from 2001:db8::1.
+            let listener = client::Socks5Stream::bind(
+                rx.await.unwrap(),
+                "2001:db8::1".to_owned(),
+                0,
+                client::Config::default(),
+            )
+            .await
+            .unwrap();
+            let bind_addr = listener.bind_addr().to_socket_addrs().unwrap().next().unwrap();
+
+            // Another host connecting first is turned away, and the client keeps waiting.
+            let mut stray = TcpStream::connect(bind_addr).await.unwrap();
+            let mut buf = [0; 1];
+            let read = timeout(Duration::from_secs(5), stray.read(&mut buf)).await.unwrap();
+            assert_eq!(read.unwrap(), 0);
+            assert!(timeout(Duration::from_millis(200), listener.accept()).await.is_err());
+        });
+    }
+
     #[test]
     fn dns_udp_assosiate_no_auth() {
         init();
@@ -38,6 +38,8 @@ pub struct Config {
     transfer_data: bool,
     /// Enable UDP support
     allow_udp: bool,
+    /// Enable BIND support
+    allow_bind: bool,
     auth: Option<Arc<dyn Authentication>>,
 }
 
@@ -50,6 +52,7 @@ impl Default for Config {
             execute_command: true,
             transfer_data: true,
             allow_udp: false,
+            allow_bind: false,
             auth: None,
         }
     }
@@ -122,6 +125,12 @@ impl Config {
         self.allow_udp = value;
         self
     }
+
+    /// Set whether or not to allow the BIND command
+    pub fn set_bind_support(&mut self, value: bool) -> &mut Self {
+        self.allow_bind = value;
+        self
+    }
 }
 
 /// Wrapper of TcpListener
@@ -440,7 +449,10 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
     }
 
     /// Reply error to the client with the reply code according to the RFC.
-    async fn reply_error(&mut self, error: &ReplyError) -> Result<()> {
+    ///
+    /// Public so that a caller which turned off `Config::execute_command` can report
+    /// the result of the command it executed on its own.
+    pub async fn reply_error(&mut self, error: &ReplyError) -> Result<()> {
         let reply = new_reply(error, "0.0.0.0:0".parse().unwrap());
         debug!("reply error to be written: {:?}", &reply);
 
@@ -454,6 +466,22 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         Ok(())
     }
 
+    /// Reply success to the client, with the address the command is bound to.
+    ///
+    /// To be used when `Config::execute_command` has been turned off, once the caller
+    /// executed the command on its own (e.g. connected through another proxy).
+    pub async fn reply_success(&mut self, sock_addr: SocketAddr) -> Result<()> {
+        self.inner
+            .write(&new_reply(&ReplyError::Succeeded, sock_addr))
+            .await
+            .context("Can't write successful reply")?;
+
+        self.inner.flush().await.context("Can't flush the reply!")?;
+
+        debug!("Wrote success");
+        Ok(())
+    }
+
     /// Decide to whether or not, accept the authentication method.
     /// Don't forget that the methods list sent by the client, contains one or more methods.
     ///
@@ -495,7 +523,12 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
                     }
                     self.cmd = Some(cmd);
                 }
-                Socks5Command::TCPBind => return Err(ReplyError::CommandNotSupported.into()),
+                Socks5Command::TCPBind => {
+                    if !self.config.allow_bind {
+                        return Err(ReplyError::CommandNotSupported.into());
+                    }
+                    self.cmd = Some(cmd);
+                }
             },
         }
 
@@ -536,7 +569,13 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         match &self.cmd {
             None => Err(ReplyError::CommandNotSupported.into()),
             Some(cmd) => match cmd {
-                Socks5Command::TCPBind => Err(ReplyError::CommandNotSupported.into()),
+                Socks5Command::TCPBind => {
+                    if self.config.allow_bind {
+                        return self.execute_command_bind().await;
+                    } else {
+                        Err(ReplyError::CommandNotSupported.into())
+                    }
+                }
                 Socks5Command::TCPConnect => return self.execute_command_connect().await,
                 Socks5Command::UDPAssociate => {
                     if self.config.allow_udp {
@@ -612,6 +651,69 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         Ok(())
     }
 
+    /// Listen on a random TCP port for the connection the client expects from
+    /// a remote host, reply once listening and once it came in, then forward
+    /// the data between them (client <=> remote host).
+    async fn execute_command_bind(&mut self) -> Result<()> {
+        // The DST.ADDR and DST.PORT fields contain the address and port that
+        // the client expects the connection to come from. The server MAY use
+        // this information to limit access to the listener.
+        // @see Page 5, https://datatracker.ietf.org/doc/html/rfc1928.
+        //
+        // Only a remote host coming from DST.ADDR is accepted, unless it is unspecified.
+        // Its source port is seldom known by the client, so DST.PORT is not checked.
+        let expected = match self.target_addr.clone() {
+            Some(TargetAddr::Ip(addr)) if addr.ip().is_unspecified() => None,
+            Some(TargetAddr::Ip(addr)) => Some(addr.ip()),
+            Some(domain) => match domain.resolve_dns().await? {
+                TargetAddr::Ip(addr) => Some(addr.ip()),
+                TargetAddr::Domain(_, _) => return Err(ReplyError::HostUnreachable.into()),
+            },
+            None => None,
+        };
+
+        // Listen on the address the client reached us on, so it can be
+        // handed over to the remote host.
+        let listener = TcpListener::bind(SocketAddr::new(
+            self.reply_ip.context("invalid reply ip")?,
+            0,
+        ))
+        .await?;
+
+        // First reply, with the address the remote host has to connect to.
+        self.reply_success(listener.local_addr()?).await?;
+
+        let limit = Duration::from_secs(self.config.request_timeout);
+        let accept_expected = async {
+            loop {
+                let (inbound, peer_addr) = listener.accept().await?;
+                match expected {
+                    // dropping the connection turns the unexpected host away
+                    Some(ip) if ip.to_canonical() != peer_addr.ip().to_canonical() => {
+                        debug!("Refused remote host {}, expected {}", peer_addr, ip);
+                    }
+                    _ => return io::Result::Ok((inbound, peer_addr)),
+                }
+            }
+        };
+        let (inbound, peer_addr) = match timeout(limit, accept_expected).await {
+            Ok(accepted) => accepted?,
+            // Wrap timeout error in a proper ReplyError
+            Err(_) => return Err(ReplyError::TtlExpired.into()),
+        };
+        debug!("Remote host connected from {}", peer_addr);
+
+        // Second reply, with the address of the remote host.
+        self.reply_success(peer_addr).await?;
+
+        if self.config.transfer_data {
+            transfer(&mut self.inner, inbound).await?;
+            debug!("Transfer success");
+        }
+
+        Ok(())
+    }
+
     /// Bind to a random UDP port, wait for the traffic from
     /// the client, and then forward the data to the remote addr.
     async fn execute_command_udp_assoc(&mut self) -> Result<()> {
@@ -654,9 +756,19 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         self.target_addr.as_ref()
     }
 
+    /// The command requested by the client, to be executed by the caller when
+    /// `Config::execute_command` has been turned off.
+    pub fn cmd(&self) -> Option<&Socks5Command> {
+        self.cmd.as_ref()
+    }
+
     pub fn auth(&self) -> &AuthenticationMethod {
         &self.auth
     }
+
+    pub fn get_socket_ref(&self) -> &T {
+        &self.inner
+    }
 }
 
 /// Copy data between two peers
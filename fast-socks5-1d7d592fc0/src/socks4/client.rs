@@ -94,15 +94,16 @@ where
     }
 
     async fn send_command_request(&mut self, cmd: &Socks4Command) -> Result<()> {
-        let mut packet = [0u8; MAX_ADDR_LEN];
-        packet[0] = consts::SOCKS4_VERSION;
-        packet[1] = cmd.as_u8();
+        let mut packet = Vec::with_capacity(MAX_ADDR_LEN);
+        packet.push(consts::SOCKS4_VERSION);
+        packet.push(cmd.as_u8());
 
         match &self.target_addr {
             Some(TargetAddr::Ip(SocketAddr::V4(addr))) => {
-                packet[2] = (addr.port() >> 8) as u8;
-                packet[3] = addr.port() as u8;
-                packet[4..8].copy_from_slice(&(addr.ip()).octets());
+                packet.extend_from_slice(&addr.port().to_be_bytes());
+                packet.extend_from_slice(&addr.ip().octets());
+                // empty USERID
+                packet.push(0);
                 Ok(())
             }
             Some(TargetAddr::Ip(SocketAddr::V6(addr))) => {
@@ -110,12 +111,18 @@ where
                 Err(ReplySocks4Error(ReplyError::AddressTypeNotSupported))
             }
             Some(TargetAddr::Domain(domain, port)) => {
-                packet[2] = (port >> 8) as u8;
-                packet[3] = *port as u8;
-                packet[4..8].copy_from_slice(&[0, 0, 0, 1]);
+                // SOCKS4a: DSTIP is 0.0.0.x with x != 0, the domain follows the USERID
                 let domain_bytes = domain.as_bytes();
-                let offset = 8 + domain_bytes.len();
-                packet[8..offset].copy_from_slice(domain_bytes);
+                if domain_bytes.len() > MAX_ADDR_LEN - 10 || domain_bytes.contains(&0) {
+                    error!("Domain is too long or invalid: {:?}", domain);
+                    return Err(ReplySocks4Error(ReplyError::AddressTypeNotSupported));
+                }
+                packet.extend_from_slice(&port.to_be_bytes());
+                packet.extend_from_slice(&[0, 0, 0, 1]);
+                // empty USERID
+                packet.push(0);
+                packet.extend_from_slice(domain_bytes);
+                packet.push(0);
                 Ok(())
             }
             _ => {
@@ -128,7 +135,9 @@ where
 
     #[rustfmt::skip]
     async fn read_command_request(&mut self) -> Result<()> {
-        let [_, cd] = read_exact!(self.socket, [0u8; 2])?;
+        // VN, CD, DSTPORT and DSTIP, the whole reply has to be consumed
+        // before the stream carries the data of the target
+        let [_, cd, _, _, _, _, _, _] = read_exact!(self.socket, [0u8; 8])?;
         let reply = ReplyError::from_u8(cd);
         match reply {
             ReplyError::Succeeded => Ok(()),
@@ -345,6 +354,49 @@ mod tests {
         assert_response_body(&response_body);
     }
 
+    #[tokio::test]
+    pub async fn test_request_packet_socks4a() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut socks = Socks4Stream::use_stream(client).expect("should wrap to socks stream");
+
+        let proxy = tokio::spawn(async move {
+            let mut request = [0u8; 21];
+            server
+                .read_exact(&mut request)
+                .await
+                .expect("should read the request");
+            server
+                .write_all(&[0, consts::SOCKS4_REPLY_SUCCEEDED, 0, 0, 0, 0, 0, 0])
+                .await
+                .expect("should write the reply");
+            server
+                .write_all(b"hello")
+                .await
+                .expect("should write data");
+            request
+        });
+
+        socks
+            .request(
+                Socks4Command::Connect,
+                TargetAddr::Domain("example.com".to_string(), 80),
+                false,
+            )
+            .await
+            .expect("should send connect successfully");
+
+        let request = proxy.await.expect("proxy should not panic");
+        assert_eq!(&request[..9], &[4, 1, 0, 80, 0, 0, 0, 1, 0]);
+        assert_eq!(&request[9..], b"example.com\0");
+
+        let mut data = [0u8; 5];
+        socks
+            .read_exact(&mut data)
+            .await
+            .expect("should read data after the reply");
+        assert_eq!(&data, b"hello");
+    }
+
     // Need to find socks4a supporting proxy or implement
     // custom server and test using it
     //
@@ -161,6 +161,12 @@ impl<'a> ToTargetAddr for (&'a str, u16) {
     }
 }
 
+impl ToTargetAddr for TargetAddr {
+    fn to_target_addr(&self) -> io::Result<TargetAddr> {
+        Ok(self.clone())
+    }
+}
+
 impl ToTargetAddr for SocketAddr {
     fn to_target_addr(&self) -> io::Result<TargetAddr> {
         Ok(TargetAddr::Ip(*self))
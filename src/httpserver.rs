@@ -0,0 +1,521 @@
+#[forbid(unsafe_code)]
+pub mod http {
+    use log::{info, warn, error, debug};
+    use crate::proxypool::ProxyPool;
+    use crate::proxypool::route::{RouteFilter, RouteParams};
+    use crate::proxypool::session::SessionTable;
+    use crate::socksserver::socks5::{connect_through_pool, AuthMode, RelayConfig, RouteUserPassword, Target, UpstreamError};
+    use crate::utils::proxy::join_host_port;
+
+    use anyhow::{anyhow, bail, Context, Result};
+    use base64::{engine::general_purpose, Engine as _};
+    use fast_socks5::server::Authentication;
+    use fast_socks5::util::target_addr::ToTargetAddr;
+    use fast_socks5::{ReplyError, SocksError};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task;
+    use tokio::time::timeout;
+    use url::{Host, Url};
+    use std::io::ErrorKind;
+    use std::net::{IpAddr, Ipv6Addr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Longest request head accepted from a client
+    const MAX_HEAD_LEN: u64 = 64 * 1024;
+
+    /// Hop-by-hop headers which are not forwarded to the target
+    const HOP_HEADERS: [&str; 4] = ["proxy-authorization", "proxy-connection", "connection", "keep-alive"];
+
+    /// What every connection accepted by a listener is handled with.
+    struct Settings {
+        listen_addr: String,
+        request_timeout: Duration,
+        auth: Option<RouteUserPassword>,
+        filter: RouteFilter,
+    }
+
+    /// Request line and headers of a client request.
+    struct RequestHead {
+        method: String,
+        target: String,
+        version: String,
+        headers: Vec<(String, String)>,
+    }
+
+    impl RequestHead {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    pub async fn spawn_http_server(request_timeout: u64, auth: AuthMode, listen_addr: String, filter: RouteFilter, proxy_pool: ProxyPool, relay: RelayConfig, sessions: SessionTable) -> Result<()> {
+        let auth = match auth {
+            AuthMode::NoAuth => {
+                warn!("No authentication has been set!");
+                None
+            }
+            AuthMode::Password { username, password } => {
+                info!("Simple auth system has been set.");
+                Some(RouteUserPassword { username, password, filter: filter.clone() })
+            }
+        };
+        let settings = Arc::new(Settings {
+            listen_addr: listen_addr.clone(),
+            request_timeout: Duration::from_secs(request_timeout),
+            auth,
+            filter,
+        });
+
+        let listener = TcpListener::bind(&listen_addr).await?;
+
+        info!("Listen for http connections @ {}, using proxypool[{}]", &listen_addr, proxy_pool.len());
+
+        loop {
+            match listener.accept().await {
+                Ok((socket, peer_addr)) => {
+                    let settings = settings.clone();
+                    let proxy_pool = proxy_pool.clone();
+                    let sessions = sessions.clone();
+                    let relay = relay.clone();
+                    task::spawn(async move {
+                        if let Err(err) = handle_connection(socket, peer_addr.ip(), settings, proxy_pool, relay, sessions).await {
+                            error!("http handle error = {:#}", err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("accept error = {:#}", err);
+                }
+            }
+        }
+    }
+
+    async fn handle_connection(socket: TcpStream, peer_ip: IpAddr, settings: Arc<Settings>, proxy_pool: ProxyPool, relay: RelayConfig, sessions: SessionTable) -> Result<()> {
+        let mut reader = BufReader::new(socket);
+        let head = timeout(settings.request_timeout, read_head(&mut reader))
+            .await
+            .context("read request head of incoming connection")??;
+
+        // same credentials and routing parameters as the socks5 listener
+        let (user, mut route) = match &settings.auth {
+            None => (String::new(), RouteParams::default()),
+            Some(auth) => match proxy_credentials(&head) {
+                Some((username, password)) if auth.authenticate(&username, &password) => {
+                    RouteParams::parse(&username).unwrap_or_else(|_| (username.clone(), RouteParams::default()))
+                }
+                _ => {
+                    write_status(reader.get_mut(), 407, "Proxy Authentication Required", "Proxy-Authenticate: Basic realm=\"rabbithole\"\r\n").await?;
+                    return Ok(());
+                }
+            },
+        };
+        route.filter = settings.filter.narrow(&route.filter).unwrap_or(settings.filter.clone());
+
+        // CONNECT tunnels anything, other methods are forwarded with an absolute URI
+        let is_connect = head.method.eq_ignore_ascii_case("CONNECT");
+        let target = match is_connect {
+            true => parse_authority(&head.target),
+            false => parse_absolute_uri(&head.target),
+        };
+        let (host, port, path) = match target {
+            Ok(target) => target,
+            Err(err) => {
+                write_status(reader.get_mut(), 400, "Bad Request", "").await?;
+                return Err(err);
+            }
+        };
+        // the domain is only resolved here with `--dns local`
+        let target = match Target::new((host.as_str(), port).to_target_addr()?, relay.dns).await {
+            Ok(target) => target,
+            Err(err) => {
+                write_status(reader.get_mut(), 502, "Bad Gateway", "").await?;
+                return Err(err.context(format!("resolve target dns of {}:{}", host, port)));
+            }
+        };
+
+        let session_key = sessions.key(&settings.listen_addr, &user, peer_ip, &route);
+
+        // `_active` counts this connection against the upstream until it is closed
+        let (mut stream, _active) = match connect_through_pool(&proxy_pool, &relay, &sessions, session_key, &route, &target).await {
+            Ok(connected) => connected,
+            Err(err) => {
+                let (code, reason) = match &err {
+                    UpstreamError::NoMatch(_) => (503, "Service Unavailable"),
+                    UpstreamError::Failed { last: SocksError::ReplyError(ReplyError::TtlExpired), .. } => (504, "Gateway Timeout"),
+                    UpstreamError::Failed { .. } | UpstreamError::Unreachable(_) => (502, "Bad Gateway"),
+                };
+                write_status(reader.get_mut(), code, reason, "").await?;
+                return Err(anyhow!("cannot reach {}: {}", target, err));
+            }
+        };
+
+        if !is_connect {
+            stream.write_all(forward_head(&head, &host, port, &path).as_bytes()).await?;
+            return forward_one(reader, &head, stream).await;
+        }
+        reader.get_mut().write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+        // the client may have sent more than the head already
+        stream.write_all(reader.buffer()).await?;
+        let mut client = reader.into_inner();
+
+        // copy data between our incoming client and the used downstream proxy
+        match tokio::io::copy_bidirectional(&mut stream, &mut client).await {
+            Ok(res) => {
+                debug!("http transfer closed ({}, {})", res.0, res.1);
+                Ok(())
+            }
+            Err(err) => match err.kind() {
+                ErrorKind::NotConnected | ErrorKind::ConnectionReset => {
+                    debug!("http transfer closed: {}", err);
+                    Ok(())
+                },
+                _ => Err(anyhow!("http transfer error: {:#}", err)),
+            },
+        }
+    }
+
+    // Relay the body of a forwarded request and the response of the target, then
+    // close. The target has been asked to close after one response and the client
+    // is told to, so that its next request, maybe for another host, comes on a
+    // new connection instead of going to this target.
+    async fn forward_one(reader: BufReader<TcpStream>, head: &RequestHead, mut stream: TcpStream) -> Result<()> {
+        // the client may have sent more than the head already
+        let buffered = reader.buffer().to_vec();
+        let mut client = reader.into_inner();
+        let (client_read, mut client_write) = client.split();
+        let (mut upstream_read, mut upstream_write) = stream.split();
+
+        // only the body of this request, whatever the client sends after it is dropped
+        let mut body = (&buffered[..]).chain(client_read);
+        let upload = async {
+            match request_body_length(head) {
+                Some(length) => tokio::io::copy(&mut (&mut body).take(length), &mut upstream_write).await,
+                None => tokio::io::copy(&mut body, &mut upstream_write).await,
+            }
+        };
+        let download = async {
+            let mut upstream_read = BufReader::new(&mut upstream_read);
+            let mut response = read_response_head(&mut upstream_read).await?;
+            // interim responses such as `100 Continue` are followed by the final one
+            while status_of(&response).is_some_and(|status| (100..200).contains(&status) && status != 101) {
+                client_write.write_all(response.as_bytes()).await?;
+                response = read_response_head(&mut upstream_read).await?;
+            }
+            client_write.write_all(close_response_head(&response).as_bytes()).await?;
+            let copied = match response_body_length(head, &response) {
+                Some(length) => tokio::io::copy(&mut (&mut upstream_read).take(length), &mut client_write).await?,
+                None => tokio::io::copy(&mut upstream_read, &mut client_write).await?,
+            };
+            client_write.shutdown().await?;
+            debug!("http response forwarded ({} bytes of body)", copied);
+            Ok::<_, anyhow::Error>(())
+        };
+        tokio::pin!(download);
+        // the client may keep its side open for a next request, only the response is waited for
+        tokio::select! {
+            res = &mut download => res,
+            _ = upload => download.await,
+        }
+    }
+
+    // Length of the body of a request, `None` when it is chunked and runs until the client closes
+    fn request_body_length(head: &RequestHead) -> Option<u64> {
+        if head.header("Transfer-Encoding").is_some() {
+            return None;
+        }
+        Some(head.header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0))
+    }
+
+    // Length of the body of a response, `None` when it runs until the target closes
+    fn response_body_length(request: &RequestHead, response: &str) -> Option<u64> {
+        let status = status_of(response).unwrap_or(200);
+        if request.method.eq_ignore_ascii_case("HEAD") || status == 204 || status == 304 {
+            return Some(0);
+        }
+        if response_header(response, "Transfer-Encoding").is_some() {
+            return None;
+        }
+        response_header(response, "Content-Length").and_then(|l| l.parse().ok())
+    }
+
+    fn status_of(response: &str) -> Option<u16> {
+        response.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    fn response_header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    }
+
+    // Status line and headers of a response, along with the empty line ending them
+    async fn read_response_head<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Result<String> {
+        let mut limited = reader.take(MAX_HEAD_LEN);
+        let mut head = Vec::new();
+        while !head.ends_with(b"\n\n") && !head.ends_with(b"\r\n\r\n") {
+            if limited.read_until(b'\n', &mut head).await? == 0 {
+                bail!("response head of target is incomplete or longer than {} bytes", MAX_HEAD_LEN);
+            }
+        }
+        Ok(String::from_utf8_lossy(&head).into_owned())
+    }
+
+    // Response head sent to the client, without the hop-by-hop headers of the target
+    fn close_response_head(head: &str) -> String {
+        let mut lines = head.lines();
+        let mut response = format!("{}\r\n", lines.next().unwrap_or_default());
+        for line in lines.filter(|line| !line.is_empty()) {
+            let name = line.split(':').next().unwrap_or_default().trim().to_lowercase();
+            if !HOP_HEADERS.contains(&name.as_str()) {
+                response += &format!("{}\r\n", line);
+            }
+        }
+        response += "Connection: close\r\n\r\n";
+        response
+    }
+
+    async fn read_head(reader: &mut BufReader<TcpStream>) -> Result<RequestHead> {
+        let mut limited = reader.take(MAX_HEAD_LEN);
+        let mut line = String::new();
+        limited.read_line(&mut line).await?;
+        let mut parts = line.split_whitespace();
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) => (method.to_string(), target.to_string(), version.to_string()),
+            _ => bail!("invalid request line `{}`", line.trim()),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if limited.read_line(&mut line).await? == 0 {
+                bail!("request head is incomplete or longer than {} bytes", MAX_HEAD_LEN);
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            match header.split_once(':') {
+                Some((name, value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+                None => bail!("invalid header `{}`", header),
+            }
+        }
+        Ok(RequestHead { method, target, version, headers })
+    }
+
+    // Username and password of `Proxy-Authorization: Basic ...`
+    fn proxy_credentials(head: &RequestHead) -> Option<(String, String)> {
+        let value = head.header("Proxy-Authorization")?;
+        let (scheme, encoded) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+
+    // `host:port` of a CONNECT request, such as `example.com:443` or `[::1]:443`
+    fn parse_authority(target: &str) -> Result<(String, u16, String)> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("invalid CONNECT target `{}`", target))?;
+        let port = port
+            .parse()
+            .with_context(|| format!("invalid port in CONNECT target `{}`", target))?;
+        // an IPv6 address has to be in brackets, or its last group would be taken as the port
+        let host = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+            Some(ip) if ip.parse::<Ipv6Addr>().is_ok() => ip,
+            Some(_) => bail!("invalid IPv6 address in CONNECT target `{}`", target),
+            None if host.is_empty() || host.contains([':', '[', ']']) => bail!("invalid host in CONNECT target `{}`", target),
+            None => host,
+        };
+        Ok((host.to_string(), port, String::new()))
+    }
+
+    // `http://host[:port]/path?query`, returns the path to send to the target
+    fn parse_absolute_uri(target: &str) -> Result<(String, u16, String)> {
+        let url = Url::parse(target).with_context(|| format!("invalid request target `{}`", target))?;
+        if url.scheme() != "http" {
+            bail!("only http:// can be forwarded, use CONNECT for `{}`", target);
+        }
+        let host = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => bail!("host not found in `{}`", target),
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        Ok((host, port, path))
+    }
+
+    // Request head sent to the target, one request per upstream connection
+    fn forward_head(head: &RequestHead, host: &str, port: u16, path: &str) -> String {
+        let mut forwarded = format!("{} {} {}\r\n", head.method, path, head.version);
+        if head.header("Host").is_none() {
+            match port {
+                80 if host.contains(':') => forwarded += &format!("Host: [{}]\r\n", host),
+                80 => forwarded += &format!("Host: {}\r\n", host),
+                _ => forwarded += &format!("Host: {}\r\n", join_host_port(host, port)),
+            }
+        }
+        for (name, value) in &head.headers {
+            if !HOP_HEADERS.contains(&name.to_lowercase().as_str()) {
+                forwarded += &format!("{}: {}\r\n", name, value);
+            }
+        }
+        forwarded += "Connection: close\r\n\r\n";
+        forwarded
+    }
+
+    async fn write_status(socket: &mut TcpStream, code: u16, reason: &str, extra_headers: &str) -> Result<()> {
+        let response = format!(
+            "HTTP/1.1 {} {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            code, reason, extra_headers
+        );
+        socket.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn request(method: &str, target: &str, headers: &[(&str, &str)]) -> RequestHead {
+            RequestHead {
+                method: method.to_string(),
+                target: target.to_string(),
+                version: String::from("HTTP/1.1"),
+                headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            }
+        }
+
+        #[test]
+        fn parse_authority_of_domain_and_ip() {
+            assert_eq!(parse_authority("example.com:443").unwrap(), (String::from("example.com"), 443, String::new()));
+            assert_eq!(parse_authority("1.2.3.4:8443").unwrap().0, "1.2.3.4");
+            assert_eq!(parse_authority("[::1]:443").unwrap(), (String::from("::1"), 443, String::new()));
+        }
+
+        #[test]
+        fn parse_authority_rejects_invalid_targets() {
+            // bracketless IPv6, the last group would be taken as the port
+            assert!(parse_authority("::1:443").is_err());
+            assert!(parse_authority("[example.com]:443").is_err());
+            assert!(parse_authority("[::1:443").is_err());
+            assert!(parse_authority("example.com").is_err());
+            assert!(parse_authority("example.com:https").is_err());
+            assert!(parse_authority("example.com:70000").is_err());
+            assert!(parse_authority(":443").is_err());
+        }
+
+        #[test]
+        fn parse_absolute_uri_keeps_path_and_query() {
+            let (host, port, path) = parse_absolute_uri("http://example.com:8080/a/b?c=d").unwrap();
+            assert_eq!((host.as_str(), port, path.as_str()), ("example.com", 8080, "/a/b?c=d"));
+            let (host, port, path) = parse_absolute_uri("http://example.com").unwrap();
+            assert_eq!((host.as_str(), port, path.as_str()), ("example.com", 80, "/"));
+        }
+
+        #[test]
+        fn parse_absolute_uri_of_ipv6_host() {
+            let (host, port, _) = parse_absolute_uri("http://[::1]:8080/").unwrap();
+            assert_eq!((host.as_str(), port), ("::1", 8080));
+        }
+
+        #[test]
+        fn parse_absolute_uri_rejects_other_schemes() {
+            assert!(parse_absolute_uri("https://example.com/").is_err());
+            assert!(parse_absolute_uri("/relative").is_err());
+        }
+
+        #[test]
+        fn forward_head_adds_host_and_drops_hop_headers() {
+            let head = request("GET", "http://example.com:8080/", &[
+                ("Accept", "*/*"),
+                ("Proxy-Authorization", "Basic YTpi"),
+                ("Proxy-Connection", "keep-alive"),
+                ("Connection", "keep-alive"),
+                ("Keep-Alive", "timeout=5"),
+            ]);
+            assert_eq!(
+                forward_head(&head, "example.com", 8080, "/"),
+                "GET / HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+            );
+            assert!(forward_head(&head, "example.com", 80, "/").contains("Host: example.com\r\n"));
+        }
+
+        #[test]
+        fn forward_head_brackets_ipv6_host() {
+            let head = request("GET", "http://[::1]:8080/", &[]);
+            assert!(forward_head(&head, "::1", 8080, "/").contains("Host: [::1]:8080\r\n"));
+            assert!(forward_head(&head, "::1", 80, "/").contains("Host: [::1]\r\n"));
+        }
+
+        #[test]
+        fn forward_head_keeps_host_of_client() {
+            let head = request("POST", "http://example.com/", &[("Host", "example.com")]);
+            let forwarded = forward_head(&head, "example.com", 80, "/submit");
+            assert!(forwarded.starts_with("POST /submit HTTP/1.1\r\nHost: example.com\r\n"));
+            assert_eq!(forwarded.matches("Host:").count(), 1);
+        }
+
+        #[test]
+        fn close_response_head_replaces_hop_headers() {
+            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\r\n";
+            assert_eq!(
+                close_response_head(response),
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+            );
+        }
+
+        #[test]
+        fn request_body_length_from_headers() {
+            assert_eq!(request_body_length(&request("GET", "/", &[])), Some(0));
+            assert_eq!(request_body_length(&request("POST", "/", &[("content-length", "12")])), Some(12));
+            assert_eq!(request_body_length(&request("POST", "/", &[("Transfer-Encoding", "chunked")])), None);
+        }
+
+        #[test]
+        fn response_body_length_from_status_and_headers() {
+            let get = request("GET", "/", &[]);
+            assert_eq!(response_body_length(&get, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"), Some(5));
+            assert_eq!(response_body_length(&get, "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n"), None);
+            assert_eq!(response_body_length(&get, "HTTP/1.1 200 OK\r\n\r\n"), None);
+            assert_eq!(response_body_length(&get, "HTTP/1.1 204 No Content\r\n\r\n"), Some(0));
+            assert_eq!(response_body_length(&get, "HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n"), Some(0));
+            let head = request("HEAD", "/", &[]);
+            assert_eq!(response_body_length(&head, "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"), Some(0));
+        }
+
+        #[test]
+        fn proxy_credentials_of_basic_auth() {
+            // alice:pa:ss, the password may contain a colon
+            let head = request("GET", "/", &[("Proxy-Authorization", "Basic YWxpY2U6cGE6c3M=")]);
+            assert_eq!(proxy_credentials(&head), Some((String::from("alice"), String::from("pa:ss"))));
+            let head = request("GET", "/", &[("proxy-authorization", "basic YWxpY2U6cGE6c3M=")]);
+            assert!(proxy_credentials(&head).is_some());
+        }
+
+        #[test]
+        fn proxy_credentials_rejects_other_schemes() {
+            assert_eq!(proxy_credentials(&request("GET", "/", &[])), None);
+            assert_eq!(proxy_credentials(&request("GET", "/", &[("Proxy-Authorization", "Bearer YWxpY2U6cGE6c3M=")])), None);
+            assert_eq!(proxy_credentials(&request("GET", "/", &[("Proxy-Authorization", "Basic !!!")])), None);
+            // no colon between username and password
+            assert_eq!(proxy_credentials(&request("GET", "/", &[("Proxy-Authorization", "Basic YWxpY2U=")])), None);
+        }
+    }
+}
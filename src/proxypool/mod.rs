@@ -0,0 +1,427 @@
+pub mod route;
+pub mod session;
+pub mod store;
+pub mod strategy;
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+use log::{debug, error, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::search_api::source::SourceRegistry;
+use crate::utils::check::{test_connect, CheckConfig};
+use crate::utils::proxy::Proxy;
+use route::RouteFilter;
+use strategy::{SelectStrategy, StrategyKind};
+
+/// A pool member, and the client connections it relays right now.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub proxy: Proxy,
+    /// Identifies the member in the pool, see `Proxy::key`
+    pub key: String,
+    active: Arc<AtomicUsize>,
+}
+
+impl Upstream {
+    pub fn new(proxy: Proxy) -> Self {
+        Self {
+            key: proxy.key(),
+            proxy,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// How many client connections are relayed through this member right now.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts a client connection against an upstream for as long as it is alive.
+pub struct ActiveConnection(Arc<AtomicUsize>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The live proxy pool, shared between the socks server and the background
+/// tasks which keep it up to date. Cloning only clones the handle.
+#[derive(Clone)]
+pub struct ProxyPool {
+    upstreams: Arc<RwLock<Vec<Upstream>>>,
+    /// Every strategy is kept around, so a client can ask for another one than `strategy`
+    strategies: Arc<HashMap<StrategyKind, Box<dyn SelectStrategy>>>,
+    strategy: StrategyKind,
+    /// Evict a member after this many connect failures in a row, 0 to never evict
+    max_failures: u32,
+    /// Where `persist` saves the pool, see `--pool-file`
+    store: Option<PathBuf>,
+}
+
+impl ProxyPool {
+    pub fn new(proxies: Vec<Proxy>) -> Self {
+        let upstreams = proxies.into_iter().map(Upstream::new).collect();
+        let strategies = StrategyKind::value_variants()
+            .iter()
+            .map(|kind| (*kind, kind.build()))
+            .collect();
+        Self {
+            upstreams: Arc::new(RwLock::new(upstreams)),
+            strategies: Arc::new(strategies),
+            strategy: StrategyKind::Random,
+            max_failures: 0,
+            store: None,
+        }
+    }
+
+    pub fn set_store(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.store = path;
+        self
+    }
+
+    /// Save the pool to its store, if one has been set.
+    pub fn persist(&self) {
+        let path = match &self.store {
+            Some(path) => path,
+            None => return,
+        };
+        let proxies = self.members();
+        match store::save(path, &proxies) {
+            Ok(_) => debug!("Saved proxypool[{}] to {}", proxies.len(), path.display()),
+            Err(e) => error!("Failed to save proxypool to {}: {}", path.display(), e),
+        }
+    }
+
+    pub fn set_strategy(&mut self, strategy: StrategyKind) -> &mut Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn set_max_failures(&mut self, n: u32) -> &mut Self {
+        self.max_failures = n;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.upstreams.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copy of the current members with their metadata and health, used to
+    /// check them without holding the lock.
+    pub fn members(&self) -> Vec<Proxy> {
+        self.upstreams
+            .read()
+            .unwrap()
+            .iter()
+            .map(|u| u.proxy.clone())
+            .collect()
+    }
+
+    /// Keys of the current members.
+    pub fn snapshot(&self) -> Vec<String> {
+        self.upstreams
+            .read()
+            .unwrap()
+            .iter()
+            .map(|u| u.key.clone())
+            .collect()
+    }
+
+    /// Pick a member matching `filter` which is not in `tried`, with `strategy` or
+    /// the configured one. The connection is counted against the member until the
+    /// returned guard is dropped.
+    pub fn select_excluding(
+        &self,
+        tried: &[String],
+        filter: &RouteFilter,
+        strategy: Option<StrategyKind>,
+    ) -> Option<(Proxy, ActiveConnection)> {
+        let upstreams = self.upstreams.read().unwrap();
+        let candidates: Vec<&Upstream> = upstreams
+            .iter()
+            .filter(|u| !tried.contains(&u.key) && filter.matches(&u.proxy))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let strategy = &self.strategies[&strategy.unwrap_or(self.strategy)];
+        let chosen = candidates[strategy.select(&candidates)];
+        chosen.active.fetch_add(1, Ordering::Relaxed);
+        Some((chosen.proxy.clone(), ActiveConnection(chosen.active.clone())))
+    }
+
+    /// How many members match `filter`, leaving out the ones in `excluding`.
+    pub fn count_matching(&self, filter: &RouteFilter, excluding: &[String]) -> usize {
+        let upstreams = self.upstreams.read().unwrap();
+        upstreams
+            .iter()
+            .filter(|u| !excluding.contains(&u.key) && filter.matches(&u.proxy))
+            .count()
+    }
+
+    /// Up to `count` members matching `filter` picked at random, leaving out the
+    /// ones in `excluding`.
+    pub fn pick_random(&self, count: usize, filter: &RouteFilter, excluding: &[String]) -> Vec<Proxy> {
+        let upstreams = self.upstreams.read().unwrap();
+        let candidates: Vec<&Upstream> = upstreams
+            .iter()
+            .filter(|u| !excluding.contains(&u.key) && filter.matches(&u.proxy))
+            .collect();
+        candidates
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .map(|u| u.proxy.clone())
+            .collect()
+    }
+
+    /// Count a connection against the member `key`, `None` if it is not in the
+    /// pool (anymore) or does not match `filter`.
+    pub fn acquire(&self, key: &str, filter: &RouteFilter) -> Option<(Proxy, ActiveConnection)> {
+        let upstreams = self.upstreams.read().unwrap();
+        let u = upstreams
+            .iter()
+            .find(|u| u.key == key && filter.matches(&u.proxy))?;
+        u.active.fetch_add(1, Ordering::Relaxed);
+        Some((u.proxy.clone(), ActiveConnection(u.active.clone())))
+    }
+
+    /// Record a successful connection through the member `key`.
+    pub fn report_success(&self, key: &str) {
+        let mut upstreams = self.upstreams.write().unwrap();
+        if let Some(u) = upstreams.iter_mut().find(|u| u.key == key) {
+            u.proxy.health.successes += 1;
+            u.proxy.health.consecutive_failures = 0;
+            u.proxy.health.last_success = Some(SystemTime::now());
+        }
+    }
+
+    /// Record a failed connection through the member `key`, it is evicted once it
+    /// failed `max_failures` times in a row. Returns whether it has been evicted.
+    pub fn report_failure(&self, key: &str) -> bool {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let idx = match upstreams.iter().position(|u| u.key == key) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let health = &mut upstreams[idx].proxy.health;
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        if self.max_failures > 0 && health.consecutive_failures >= self.max_failures {
+            upstreams.remove(idx);
+            return true;
+        }
+        false
+    }
+
+    /// Drop the given members, returns how many were actually removed.
+    pub fn remove(&self, dead: &[String]) -> usize {
+        let dead: HashSet<&String> = dead.iter().collect();
+        let mut upstreams = self.upstreams.write().unwrap();
+        let before = upstreams.len();
+        upstreams.retain(|u| !dead.contains(&u.key));
+        before - upstreams.len()
+    }
+
+    /// Update the exit, delay and anonymity of members which have just been
+    /// checked again, their health is kept as it is in the pool. UDP and BIND
+    /// are only probed the first time a proxy is checked, so they stay as they are.
+    pub fn update_checked(&self, checked: &[Proxy]) {
+        let checked: HashMap<String, &Proxy> = checked.iter().map(|p| (p.key(), p)).collect();
+        let mut upstreams = self.upstreams.write().unwrap();
+        for u in upstreams.iter_mut() {
+            if let Some(p) = checked.get(&u.key) {
+                u.proxy.ip = p.ip.clone();
+                u.proxy.city = p.city.clone();
+                u.proxy.country = p.country.clone();
+                u.proxy.latency = p.latency;
+                u.proxy.anonymity = p.anonymity;
+                u.proxy.health.last_check = p.health.last_check;
+            }
+        }
+    }
+
+    /// Add members which are not in the pool yet, returns how many were added.
+    pub fn extend(&self, found: Vec<Proxy>) -> usize {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let mut known: HashSet<String> = upstreams.iter().map(|u| u.key.clone()).collect();
+        let before = upstreams.len();
+        for proxy in found {
+            let upstream = Upstream::new(proxy);
+            if known.insert(upstream.key.clone()) {
+                upstreams.push(upstream);
+            }
+        }
+        upstreams.len() - before
+    }
+}
+
+/// Re-check every pool member each `interval` and evict the ones which are no
+/// longer available. Members added while a check is running are left alone.
+pub fn spawn_health_check(
+    pool: ProxyPool,
+    interval: Duration,
+    check_config: CheckConfig,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let members = pool.members();
+            if members.is_empty() {
+                warn!("Health check: proxy pool is empty, nothing to check");
+                continue;
+            }
+            debug!("Health check: re-checking {} proxy", members.len());
+
+            let checked = match test_connect(members.clone(), &check_config).await {
+                Ok(checked) => checked,
+                Err(e) => {
+                    error!("Health check failed: {}", e);
+                    continue;
+                }
+            };
+            pool.update_checked(&checked);
+            let alive: HashSet<String> = checked.iter().map(|p| p.key()).collect();
+            let dead: Vec<String> = members
+                .iter()
+                .map(|p| p.key())
+                .filter(|p| !alive.contains(p))
+                .collect();
+            let evicted = pool.remove(&dead);
+
+            info!(
+                "Health check: {} alive, {} evicted, proxypool[{}]",
+                alive.len(),
+                evicted,
+                pool.len()
+            );
+            if pool.is_empty() {
+                warn!("Health check: every proxy has been evicted, the pool is empty now");
+            }
+            pool.persist();
+        }
+    })
+}
+
+/// Fetch from every source of `registry` again each `interval`, check only the
+/// proxy which are not in the pool yet and merge the available ones into it.
+pub fn spawn_refresh(
+    pool: ProxyPool,
+    interval: Duration,
+    check_config: CheckConfig,
+    registry: Arc<SourceRegistry>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            info!("Refresh: searching for new proxy...");
+            let mut known: HashSet<String> = pool.snapshot().into_iter().collect();
+            let found = registry.fetch_all(&mut known).await;
+            if found.is_empty() {
+                registry.log_stats();
+                info!("Refresh: no new proxy found, proxypool[{}]", pool.len());
+                continue;
+            }
+            info!("Refresh: found {} new proxy, now checking availability", found.len());
+
+            let available = match test_connect(found, &check_config).await {
+                Ok(available) => available,
+                Err(e) => {
+                    error!("Refresh failed: {}", e);
+                    continue;
+                }
+            };
+            registry.record_validated(&available);
+            registry.log_stats();
+            let added = pool.extend(available);
+            info!("Refresh: {} proxy added, proxypool[{}]", added, pool.len());
+            pool.persist();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::proxy::Scheme;
+
+    fn proxy(port: u16, country: &str) -> Proxy {
+        let mut proxy = Proxy::new(Scheme::Socks5, String::from("127.0.0.1"), port, "test");
+        proxy.country = country.to_string();
+        proxy
+    }
+
+    fn pool() -> ProxyPool {
+        let mut pool = ProxyPool::new(vec![proxy(1, "US"), proxy(2, "DE"), proxy(3, "US")]);
+        pool.set_strategy(StrategyKind::RoundRobin);
+        pool
+    }
+
+    #[test]
+    fn select_excluding_skips_tried_and_filtered() {
+        let pool = pool();
+        let keys = pool.snapshot();
+        let us = RouteFilter { country: Some(String::from("US")), ..Default::default() };
+
+        let (chosen, _guard) = pool.select_excluding(&keys[..1], &us, None).unwrap();
+        assert_eq!(chosen.key(), keys[2]);
+        assert!(pool.select_excluding(&[keys[0].clone(), keys[2].clone()], &us, None).is_none());
+        assert!(pool.select_excluding(&keys, &RouteFilter::default(), None).is_none());
+    }
+
+    #[test]
+    fn select_excluding_uses_requested_strategy() {
+        let pool = pool();
+        let keys = pool.snapshot();
+        let filter = RouteFilter::default();
+        let _busy = pool.select_excluding(&keys[1..], &filter, None).unwrap();
+
+        // the first member relays a connection now, so the least loaded is the second
+        let (chosen, _guard) = pool.select_excluding(&[], &filter, Some(StrategyKind::LeastConnections)).unwrap();
+        assert_eq!(chosen.key(), keys[1]);
+    }
+
+    #[test]
+    fn connections_are_counted_until_dropped() {
+        let pool = pool();
+        let keys = pool.snapshot();
+        let active = |key: &str| {
+            pool.upstreams.read().unwrap().iter().find(|u| u.key == key).unwrap().active_connections()
+        };
+
+        let first = pool.select_excluding(&keys[1..], &RouteFilter::default(), None).unwrap();
+        let second = pool.acquire(&keys[0], &RouteFilter::default()).unwrap();
+        assert_eq!(second.0.key(), keys[0]);
+        assert_eq!(active(&keys[0]), 2);
+        drop(first);
+        assert_eq!(active(&keys[0]), 1);
+        drop(second);
+        assert_eq!(active(&keys[0]), 0);
+    }
+
+    #[test]
+    fn acquire_misses_evicted_or_filtered() {
+        let pool = pool();
+        let keys = pool.snapshot();
+        let us = RouteFilter { country: Some(String::from("US")), ..Default::default() };
+
+        assert!(pool.acquire(&keys[1], &us).is_none());
+        assert!(pool.acquire(&keys[0], &us).is_some());
+        pool.remove(&keys[..1]);
+        assert!(pool.acquire(&keys[0], &us).is_none());
+        assert!(pool.acquire("socks5://127.0.0.1:9", &RouteFilter::default()).is_none());
+    }
+}
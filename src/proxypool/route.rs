@@ -0,0 +1,282 @@
+use clap::ValueEnum;
+
+use super::strategy::StrategyKind;
+use crate::utils::check::in_zone;
+use crate::utils::proxy::Proxy;
+
+const ROUTE_KEYS: [&str; 5] = ["country", "city", "session", "rotate", "strategy"];
+
+/// How a client wants its session to be routed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Keep the session on the same upstream proxy (with `--sticky username`)
+    Sticky,
+    /// Pick a new upstream proxy for every connection
+    Request,
+}
+
+/// Which pool members may serve a connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteFilter {
+    /// ISO 3166 country code, as reported by ipinfo.io
+    pub country: Option<String>,
+    pub city: Option<String>,
+    /// Same as `-z --zone`, only set by a listener
+    pub zone: Option<i8>,
+    /// Only members relaying UDP, set for UDP ASSOCIATE
+    pub udp: bool,
+    /// Only members accepting BIND, set for BIND
+    pub bind: bool,
+}
+
+impl RouteFilter {
+    pub fn matches(&self, proxy: &Proxy) -> bool {
+        if let Some(country) = &self.country {
+            if !proxy.country.eq_ignore_ascii_case(country) {
+                return false;
+            }
+        }
+        if let Some(city) = &self.city {
+            if !proxy.city.eq_ignore_ascii_case(city) {
+                return false;
+            }
+        }
+        if let Some(zone) = self.zone {
+            if !in_zone(zone, &proxy.country) {
+                return false;
+            }
+        }
+        if self.udp && !proxy.udp {
+            return false;
+        }
+        if self.bind && !proxy.bind {
+            return false;
+        }
+        true
+    }
+
+    /// Restrict the filter of a listener with the one a client asked for. A
+    /// client cannot leave the members its listener is limited to, so asking
+    /// for another country or city is an error.
+    pub fn narrow(&self, client: &RouteFilter) -> Result<RouteFilter, String> {
+        let pick = |listener: &Option<String>, client: &Option<String>, what: &str| match (listener, client) {
+            (Some(l), Some(c)) if !l.eq_ignore_ascii_case(c) => {
+                Err(format!("{} `{}` is not allowed on this listener, only `{}`", what, c, l))
+            }
+            (Some(l), _) => Ok(Some(l.clone())),
+            (None, c) => Ok(c.clone()),
+        };
+        let country = pick(&self.country, &client.country, "country")?;
+        let city = pick(&self.city, &client.city, "city")?;
+        if let (Some(zone), Some(country)) = (self.zone, &country) {
+            if !in_zone(zone, country) {
+                return Err(format!("country `{}` is out of zone {} of this listener", country, zone));
+            }
+        }
+        Ok(RouteFilter { country, city, zone: self.zone, udp: self.udp || client.udp, bind: self.bind || client.bind })
+    }
+}
+
+/// Routing parameters a client encoded in its SOCKS5 username, such as
+/// `user-country-US-city-new_york-session-42-strategy-least-latency`.
+#[derive(Debug, Clone)]
+pub struct RouteParams {
+    pub filter: RouteFilter,
+    pub session: Option<String>,
+    pub rotation: Rotation,
+    pub strategy: Option<StrategyKind>,
+}
+
+impl Default for RouteParams {
+    fn default() -> Self {
+        Self {
+            filter: RouteFilter::default(),
+            session: None,
+            rotation: Rotation::Sticky,
+            strategy: None,
+        }
+    }
+}
+
+impl RouteParams {
+    /// Check that the username of a listener leaves its routing parameters to
+    /// the clients: one containing a route key would have that key taken as the
+    /// start of the routing parameters, and never authenticate.
+    pub fn check_base(username: &str) -> Result<(), String> {
+        match username.split('-').find(|t| ROUTE_KEYS.contains(&t.to_lowercase().as_str())) {
+            Some(key) => Err(format!(
+                "username `{}` cannot contain `{}` between dashes, it is kept for routing parameters",
+                username, key
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Split a username into the base username used for authentication and the
+    /// routing parameters which follow it. Unknown values are an error so that a
+    /// typo does not silently route through the whole pool.
+    pub fn parse(username: &str) -> Result<(String, RouteParams), String> {
+        let tokens: Vec<&str> = username.split('-').collect();
+        let first_key = tokens
+            .iter()
+            .position(|t| ROUTE_KEYS.contains(&t.to_lowercase().as_str()))
+            .unwrap_or(tokens.len());
+        let base = tokens[..first_key].join("-");
+
+        let mut params = RouteParams::default();
+        let mut rest = &tokens[first_key..];
+        while let Some((key, tail)) = rest.split_first() {
+            // a value runs until the next known key, so it may contain `-` itself
+            let len = tail
+                .iter()
+                .position(|t| ROUTE_KEYS.contains(&t.to_lowercase().as_str()))
+                .unwrap_or(tail.len());
+            let value = tail[..len].join("-");
+            rest = &tail[len..];
+            if value.is_empty() {
+                return Err(format!("missing value of `{}` in username", key));
+            }
+
+            match key.to_lowercase().as_str() {
+                "country" => params.filter.country = Some(value.to_uppercase()),
+                "city" => params.filter.city = Some(value.replace('_', " ")),
+                "session" => params.session = Some(value),
+                "rotate" => {
+                    params.rotation = match value.to_lowercase().as_str() {
+                        "sticky" => Rotation::Sticky,
+                        "request" => Rotation::Request,
+                        _ => return Err(format!("unknown rotation `{}`, expected sticky or request", value)),
+                    }
+                }
+                "strategy" => params.strategy = Some(StrategyKind::from_str(&value, true)?),
+                _ => unreachable!(),
+            }
+        }
+        Ok((base, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(country: Option<&str>, city: Option<&str>, zone: Option<i8>) -> RouteFilter {
+        RouteFilter {
+            country: country.map(String::from),
+            city: city.map(String::from),
+            zone,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_plain_username() {
+        let (base, params) = RouteParams::parse("alice").unwrap();
+        assert_eq!(base, "alice");
+        assert_eq!(params.filter, RouteFilter::default());
+        assert_eq!(params.session, None);
+        assert_eq!(params.rotation, Rotation::Sticky);
+        assert_eq!(params.strategy, None);
+    }
+
+    #[test]
+    fn parse_every_key() {
+        let (base, params) =
+            RouteParams::parse("alice-country-us-city-new_york-session-42-rotate-request-strategy-round-robin").unwrap();
+        assert_eq!(base, "alice");
+        assert_eq!(params.filter.country.as_deref(), Some("US"));
+        assert_eq!(params.filter.city.as_deref(), Some("new york"));
+        assert_eq!(params.session.as_deref(), Some("42"));
+        assert_eq!(params.rotation, Rotation::Request);
+        assert_eq!(params.strategy, Some(StrategyKind::RoundRobin));
+    }
+
+    #[test]
+    fn check_base_rejects_route_keys() {
+        assert!(RouteParams::check_base("alice").is_ok());
+        assert!(RouteParams::check_base("my-team").is_ok());
+        // a key only counts between dashes
+        assert!(RouteParams::check_base("sessions-city_ops").is_ok());
+        assert!(RouteParams::check_base("session").is_err());
+        assert!(RouteParams::check_base("ops-city-x").is_err());
+        assert!(RouteParams::check_base("ops-Strategy").is_err());
+    }
+
+    #[test]
+    fn parse_values_containing_dash() {
+        let (base, params) = RouteParams::parse("my-team-session-a-b-c-strategy-least-latency").unwrap();
+        assert_eq!(base, "my-team");
+        assert_eq!(params.session.as_deref(), Some("a-b-c"));
+        assert_eq!(params.strategy, Some(StrategyKind::LeastLatency));
+    }
+
+    #[test]
+    fn parse_keys_case_insensitive() {
+        let (base, params) = RouteParams::parse("alice-Country-de-ROTATE-Sticky").unwrap();
+        assert_eq!(base, "alice");
+        assert_eq!(params.filter.country.as_deref(), Some("DE"));
+        assert_eq!(params.rotation, Rotation::Sticky);
+    }
+
+    #[test]
+    fn parse_base_username_containing_a_key() {
+        // the first route key starts the routing parameters, whatever comes before it
+        let (base, params) = RouteParams::parse("ops-city-team").unwrap();
+        assert_eq!(base, "ops");
+        assert_eq!(params.filter.city.as_deref(), Some("team"));
+        // a base username which is a key alone leaves that key without value
+        assert!(RouteParams::parse("session").is_err());
+        assert!(RouteParams::parse("ops-session").is_err());
+    }
+
+    #[test]
+    fn parse_key_without_value() {
+        assert!(RouteParams::parse("alice-country").is_err());
+        assert!(RouteParams::parse("alice-country-session-42").is_err());
+        assert!(RouteParams::parse("alice-session-42-city").is_err());
+    }
+
+    #[test]
+    fn parse_unknown_values() {
+        assert!(RouteParams::parse("alice-rotate-never").is_err());
+        assert!(RouteParams::parse("alice-strategy-fastest").is_err());
+    }
+
+    #[test]
+    fn narrow_without_listener_filter() {
+        let client = filter(Some("US"), Some("Boston"), None);
+        assert_eq!(RouteFilter::default().narrow(&client).unwrap(), client);
+    }
+
+    #[test]
+    fn narrow_keeps_listener_filter() {
+        let listener = filter(Some("US"), None, None);
+        let narrowed = listener.narrow(&filter(None, Some("Boston"), None)).unwrap();
+        assert_eq!(narrowed, filter(Some("US"), Some("Boston"), None));
+        // the same country in another case is not a conflict
+        assert_eq!(listener.narrow(&filter(Some("us"), None, None)).unwrap(), listener);
+    }
+
+    #[test]
+    fn narrow_conflicts_with_listener_filter() {
+        let listener = filter(Some("US"), Some("Boston"), None);
+        assert!(listener.narrow(&filter(Some("DE"), None, None)).is_err());
+        assert!(listener.narrow(&filter(None, Some("Denver"), None)).is_err());
+    }
+
+    #[test]
+    fn narrow_within_zone() {
+        // zone 0 is inland China only
+        let listener = filter(None, None, Some(0));
+        assert_eq!(listener.narrow(&filter(Some("CN"), None, None)).unwrap(), filter(Some("CN"), None, Some(0)));
+        assert!(listener.narrow(&filter(Some("US"), None, None)).is_err());
+    }
+
+    #[test]
+    fn narrow_merges_udp_and_bind() {
+        let listener = RouteFilter { udp: true, ..Default::default() };
+        let client = RouteFilter { bind: true, ..Default::default() };
+        let narrowed = listener.narrow(&client).unwrap();
+        assert!(narrowed.udp && narrowed.bind);
+    }
+}
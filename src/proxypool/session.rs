@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+use super::route::{RouteParams, Rotation};
+
+/// What identifies a client session with `--sticky`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StickyKind {
+    /// Every connection may leave through a different proxy
+    None,
+    /// Connections from the same client IP leave through the same proxy
+    Ip,
+    /// Connections with the same `session-<id>` in the username leave through the same proxy
+    Username,
+}
+
+/// Pins client sessions to the upstream proxy they used last, a pin expires
+/// once it has not been used for `ttl`.
+#[derive(Clone)]
+pub struct SessionTable {
+    kind: StickyKind,
+    ttl: Duration,
+    pins: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+}
+
+impl SessionTable {
+    pub fn new(kind: StickyKind, ttl: Duration) -> Self {
+        Self {
+            kind,
+            ttl,
+            pins: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The session a client connection belongs to, `None` if it is not sticky or
+    /// the client asked to rotate on every request.
+    /// It is scoped to the `listener` and the base username `user` the client
+    /// authenticated with, so that the same session id elsewhere is another session.
+    pub fn key(&self, listener: &str, user: &str, peer_ip: IpAddr, route: &RouteParams) -> Option<String> {
+        if route.rotation == Rotation::Request {
+            return None;
+        }
+        let id = match self.kind {
+            StickyKind::None => return None,
+            StickyKind::Ip => peer_ip.to_string(),
+            StickyKind::Username => route.session.clone()?,
+        };
+        Some(format!("{}@{}/{}", user, listener, id))
+    }
+
+    /// The proxy `key` is pinned to, if the pin has not expired.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut pins = self.pins.lock().unwrap();
+        match pins.get_mut(key) {
+            Some((proxy, last_used)) if last_used.elapsed() < self.ttl => {
+                *last_used = Instant::now();
+                Some(proxy.clone())
+            }
+            Some(_) => {
+                pins.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Pin `key` to `proxy`, expired pins are dropped on the way.
+    pub fn pin(&self, key: String, proxy: String) {
+        let mut pins = self.pins.lock().unwrap();
+        let ttl = self.ttl;
+        pins.retain(|_, (_, last_used)| last_used.elapsed() < ttl);
+        pins.insert(key, (proxy, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+    use crate::proxypool::route::RouteFilter;
+    use crate::proxypool::ProxyPool;
+    use crate::utils::proxy::{Proxy, Scheme};
+
+    fn route(session: &str) -> RouteParams {
+        RouteParams { session: Some(session.to_string()), ..Default::default() }
+    }
+
+    #[test]
+    fn key_by_kind() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let none = SessionTable::new(StickyKind::None, Duration::from_secs(60));
+        assert_eq!(none.key("0.0.0.0:7777", "alice", ip, &route("abc")), None);
+        let by_ip = SessionTable::new(StickyKind::Ip, Duration::from_secs(60));
+        assert_eq!(by_ip.key("0.0.0.0:7777", "", ip, &route("abc")).as_deref(), Some("@0.0.0.0:7777/10.0.0.1"));
+        let by_username = SessionTable::new(StickyKind::Username, Duration::from_secs(60));
+        assert_eq!(by_username.key("0.0.0.0:7777", "alice", ip, &RouteParams::default()), None);
+    }
+
+    #[test]
+    fn key_scoped_to_listener_and_user() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let sessions = SessionTable::new(StickyKind::Username, Duration::from_secs(60));
+        let alice = sessions.key("0.0.0.0:7777", "alice", ip, &route("abc")).unwrap();
+        let bob = sessions.key("0.0.0.0:7777", "bob", ip, &route("abc")).unwrap();
+        let other_listener = sessions.key("0.0.0.0:7778", "alice", ip, &route("abc")).unwrap();
+        assert_ne!(alice, bob);
+        assert_ne!(alice, other_listener);
+
+        sessions.pin(alice.clone(), String::from("socks5://1.2.3.4:1080"));
+        assert_eq!(sessions.get(&alice).as_deref(), Some("socks5://1.2.3.4:1080"));
+        assert_eq!(sessions.get(&bob), None);
+        assert_eq!(sessions.get(&other_listener), None);
+    }
+
+    #[test]
+    fn key_none_when_rotating_every_request() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let rotate = RouteParams { rotation: Rotation::Request, ..route("abc") };
+        let by_ip = SessionTable::new(StickyKind::Ip, Duration::from_secs(60));
+        assert_eq!(by_ip.key("0.0.0.0:7777", "alice", ip, &rotate), None);
+        let by_username = SessionTable::new(StickyKind::Username, Duration::from_secs(60));
+        assert_eq!(by_username.key("0.0.0.0:7777", "alice", ip, &rotate), None);
+        assert!(by_username.key("0.0.0.0:7777", "alice", ip, &route("abc")).is_some());
+    }
+
+    #[test]
+    fn pin_expires_after_ttl() {
+        let sessions = SessionTable::new(StickyKind::Ip, Duration::from_millis(300));
+        sessions.pin(String::from("a"), String::from("socks5://1.2.3.4:1080"));
+        sessions.pin(String::from("b"), String::from("socks5://5.6.7.8:1080"));
+
+        sleep(Duration::from_millis(200));
+        // using a pin keeps it alive for another ttl
+        assert_eq!(sessions.get("a").as_deref(), Some("socks5://1.2.3.4:1080"));
+        sleep(Duration::from_millis(200));
+        assert_eq!(sessions.get("a").as_deref(), Some("socks5://1.2.3.4:1080"));
+        assert_eq!(sessions.get("b"), None);
+
+        // expired pins are dropped when pinning another session
+        sleep(Duration::from_millis(400));
+        sessions.pin(String::from("c"), String::from("socks5://1.2.3.4:1080"));
+        assert!(!sessions.pins.lock().unwrap().contains_key("a"));
+        assert_eq!(sessions.get("c").as_deref(), Some("socks5://1.2.3.4:1080"));
+    }
+
+    #[test]
+    fn repin_after_eviction() {
+        let pool = ProxyPool::new(vec![
+            Proxy::new(Scheme::Socks5, String::from("1.2.3.4"), 1080, "test"),
+            Proxy::new(Scheme::Socks5, String::from("5.6.7.8"), 1080, "test"),
+        ]);
+        let filter = RouteFilter::default();
+        let sessions = SessionTable::new(StickyKind::Username, Duration::from_secs(60));
+        let key = sessions
+            .key("0.0.0.0:7777", "alice", "10.0.0.1".parse().unwrap(), &route("abc"))
+            .unwrap();
+        let first = pool.snapshot()[0].clone();
+        sessions.pin(key.clone(), first.clone());
+        assert!(pool.acquire(&sessions.get(&key).unwrap(), &filter).is_some());
+
+        // the pin outlives its upstream, the next connection picks another member and pins it
+        pool.remove(std::slice::from_ref(&first));
+        let pinned = sessions.get(&key).unwrap();
+        assert!(pool.acquire(&pinned, &filter).is_none());
+        let (chosen, _active) = pool.select_excluding(&[pinned], &filter, None).unwrap();
+        assert_ne!(chosen.key(), first);
+        sessions.pin(key.clone(), chosen.key());
+        assert_eq!(sessions.get(&key), Some(chosen.key()));
+    }
+}
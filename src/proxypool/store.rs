@@ -0,0 +1,43 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::utils::proxy::Proxy;
+
+/// Read the saved pool, a missing file is an empty pool.
+pub fn load(path: &Path) -> io::Result<Vec<Proxy>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Save the pool, through a temporary file so that a crash never leaves half of it.
+pub fn save(path: &Path, proxies: &[Proxy]) -> io::Result<()> {
+    let data = serde_json::to_string_pretty(proxies)?;
+    let tmp = path.with_extension("tmp");
+    create_private(&tmp)?.write_all(data.as_bytes())?;
+    fs::rename(&tmp, path)
+}
+
+// The pool holds the credentials of private proxy, so only the owner may read it
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // a file left over from before keeps its mode when opened
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    fs::File::create(path)
+}
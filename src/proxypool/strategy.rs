@@ -0,0 +1,175 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use clap::ValueEnum;
+use rand::distributions::WeightedIndex;
+use rand::prelude::Distribution;
+use rand::Rng;
+
+use super::Upstream;
+
+/// Decide which upstream proxy serves the next connection.
+///
+/// `candidates` is never empty, the returned value is an index into it.
+pub trait SelectStrategy: Send + Sync {
+    fn select(&self, candidates: &[&Upstream]) -> usize;
+}
+
+/// Strategies which can be chosen with `--strategy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum StrategyKind {
+    /// Pick a random proxy
+    Random,
+    /// Take turns over the pool
+    RoundRobin,
+    /// Prefer the proxy with the lowest delay measured when checking it
+    LeastLatency,
+    /// Prefer the proxy relaying the fewest connections right now
+    LeastConnections,
+    /// Random, weighted by how often the proxy succeeded to connect
+    Weighted,
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Box<dyn SelectStrategy> {
+        match self {
+            StrategyKind::Random => Box::new(Random),
+            StrategyKind::RoundRobin => Box::new(RoundRobin::default()),
+            StrategyKind::LeastLatency => Box::new(LeastLatency),
+            StrategyKind::LeastConnections => Box::new(LeastConnections),
+            StrategyKind::Weighted => Box::new(Weighted),
+        }
+    }
+}
+
+pub struct Random;
+
+impl SelectStrategy for Random {
+    fn select(&self, candidates: &[&Upstream]) -> usize {
+        rand::thread_rng().gen_range(0..candidates.len())
+    }
+}
+
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl SelectStrategy for RoundRobin {
+    fn select(&self, candidates: &[&Upstream]) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()
+    }
+}
+
+pub struct LeastLatency;
+
+impl SelectStrategy for LeastLatency {
+    fn select(&self, candidates: &[&Upstream]) -> usize {
+        candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, u)| u.proxy.latency)
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    }
+}
+
+pub struct LeastConnections;
+
+impl SelectStrategy for LeastConnections {
+    fn select(&self, candidates: &[&Upstream]) -> usize {
+        candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, u)| u.active_connections())
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    }
+}
+
+pub struct Weighted;
+
+impl SelectStrategy for Weighted {
+    fn select(&self, candidates: &[&Upstream]) -> usize {
+        let weights: Vec<f64> = candidates.iter().map(|u| u.proxy.health.success_rate()).collect();
+        pick_weighted(&weights)
+    }
+}
+
+// Index picked at random in proportion to `weights`, uniformly when they
+// cannot be used as weights, such as all zero
+fn pick_weighted(weights: &[f64]) -> usize {
+    match WeightedIndex::new(weights) {
+        Ok(dist) => dist.sample(&mut rand::thread_rng()),
+        Err(_) => rand::thread_rng().gen_range(0..weights.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::utils::proxy::{Proxy, Scheme};
+
+    fn upstream(port: u16) -> Upstream {
+        Upstream::new(Proxy::new(Scheme::Socks5, String::from("127.0.0.1"), port, "test"))
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let pool = [upstream(1), upstream(2), upstream(3)];
+        let candidates: Vec<&Upstream> = pool.iter().collect();
+        let strategy = RoundRobin::default();
+        let picks: Vec<usize> = (0..7).map(|_| strategy.select(&candidates)).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 1, 2, 0]);
+        // keeps turning when the candidates shrink
+        assert_eq!(strategy.select(&candidates[..2]), 1);
+    }
+
+    #[test]
+    fn least_latency_picks_fastest() {
+        let mut pool = [upstream(1), upstream(2), upstream(3)];
+        pool[0].proxy.latency = Duration::from_millis(300);
+        pool[1].proxy.latency = Duration::from_millis(100);
+        pool[2].proxy.latency = Duration::from_millis(200);
+        let candidates: Vec<&Upstream> = pool.iter().collect();
+        assert_eq!(LeastLatency.select(&candidates), 1);
+        // ties go to the first one
+        pool[2].proxy.latency = Duration::from_millis(100);
+        let candidates: Vec<&Upstream> = pool.iter().collect();
+        assert_eq!(LeastLatency.select(&candidates), 1);
+    }
+
+    #[test]
+    fn least_connections_picks_idlest() {
+        let pool = [upstream(1), upstream(2), upstream(3)];
+        pool[0].active.store(2, Ordering::Relaxed);
+        pool[1].active.store(1, Ordering::Relaxed);
+        pool[2].active.store(3, Ordering::Relaxed);
+        let candidates: Vec<&Upstream> = pool.iter().collect();
+        assert_eq!(LeastConnections.select(&candidates), 1);
+        pool[0].active.store(0, Ordering::Relaxed);
+        assert_eq!(LeastConnections.select(&candidates), 0);
+    }
+
+    #[test]
+    fn weighted_prefers_successful() {
+        let mut pool = [upstream(1), upstream(2)];
+        pool[0].proxy.health.failures = 1000;
+        pool[1].proxy.health.successes = 1000;
+        let candidates: Vec<&Upstream> = pool.iter().collect();
+        let picks = (0..100).filter(|_| Weighted.select(&candidates) == 1).count();
+        assert!(picks > 90, "picked the successful proxy {} times out of 100", picks);
+    }
+
+    #[test]
+    fn weighted_falls_back_to_uniform() {
+        assert_eq!(pick_weighted(&[0.0, 5.0, 0.0]), 1);
+        // all zero weights are not a distribution, every index stays reachable
+        let mut seen = [false; 3];
+        for _ in 0..200 {
+            seen[pick_weighted(&[0.0, 0.0, 0.0])] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+}
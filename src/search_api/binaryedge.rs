@@ -0,0 +1,144 @@
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use reqwest;
+use serde_json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::search_api::client::{api_key_header, log_quota, message_of, search_client, send_json, Credits};
+use crate::search_api::error::SearchError;
+use crate::search_api::source::{with_scheme, ProxySource};
+use crate::utils::proxy::{join_host_port, Proxy, Scheme};
+
+// BinaryEdge returns 20 events per page and bills one request per page
+const PAGE_SIZE: usize = 20;
+
+// BinaryEdge only answers HTTP 429 when requests come too fast
+fn rate_limited(_data: &serde_json::Value) -> bool {
+    false
+}
+
+// binaryedge answers errors such as `{"status": 400, "message": "..."}`
+fn error_of(data: &serde_json::Value) -> Option<SearchError> {
+    if !data["events"].is_null() || data["status"].is_null() {
+        return None;
+    }
+    Some(SearchError::from_message(
+        format!("{}: {}", data["status"], message_of(data)),
+        &["x-key", "unauthorized", "invalid key"],
+        &["subscription", "requests left"],
+    ))
+}
+
+// type:"socks5" country:"CN"
+async fn query_binaryedge(
+    client: &reqwest::Client,
+    query: &str,
+    page_num: i32,
+    token: &str,
+) -> Result<serde_json::Value, SearchError> {
+    let binaryedge_api_url = String::from("https://api.binaryedge.io/v2/query/search");
+
+    let mut params = HashMap::new();
+    params.insert("query", query);
+    let param_num_str = &page_num.to_string()[..];
+    params.insert("page", param_num_str);
+
+    let req = client
+        .get(binaryedge_api_url)
+        .query(&params)
+        .headers(api_key_header("X-Key", token)?);
+    let data = send_json("binaryedge", req, rate_limited).await?;
+    if let Some(e) = error_of(&data) {
+        return Err(e);
+    }
+    if !data["events"].is_array() {
+        return Err(SearchError::Malformed(String::from("no events in the answer of binaryedge")));
+    }
+    Ok(data)
+}
+
+// Requests left on the subscription
+async fn quota_binaryedge(client: &reqwest::Client, token: &str) -> Option<String> {
+    let req = client
+        .get("https://api.binaryedge.io/v2/user/subscription")
+        .headers(api_key_header("X-Key", token).ok()?);
+    let data = send_json("binaryedge", req, rate_limited).await.ok()?;
+    match &data["requests_left"] {
+        serde_json::Value::Number(n) => Some(format!("{} requests left", n)),
+        _ => None,
+    }
+}
+
+/// Page through the results of `query`, `page_num` pages at most, without spending
+/// more than `budget` requests on this run.
+pub async fn get_socks5_proxy_binaryedge(
+    query: &str,
+    page_num: i32,
+    token: &str,
+    budget: Option<u64>,
+    search_proxy: &str,
+) -> Result<Vec<Proxy>, SearchError> {
+    info!("{}", "Searching from binaryedge...");
+    let client = search_client(search_proxy, Duration::from_secs(30))?;
+    log_quota("binaryedge", "before", quota_binaryedge(&client, token).await);
+
+    let mut result: Vec<Proxy> = Vec::new();
+    let mut credits = Credits::new("binaryedge", budget);
+    for page in 1..page_num + 1 {
+        if !credits.allows(1) {
+            break;
+        }
+        let data = match query_binaryedge(&client, query, page, token).await {
+            Ok(data) => data,
+            Err(e) if result.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("  {}, keeping the {} proxy found so far", e, result.len());
+                break;
+            }
+        };
+        credits.spend(1);
+        let events = data["events"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for i in events.iter() {
+            let target = &i["target"];
+            let (ip, port) = match (target["ip"].as_str(), target["port"].as_u64()) {
+                (Some(ip), Some(port)) => (ip, port),
+                _ => continue,
+            };
+            match Proxy::parse(&join_host_port(ip, port), "binaryedge") {
+                Ok(proxy) => result.push(proxy),
+                Err(e) => debug!("  {}", e),
+            }
+        }
+        if events.len() < PAGE_SIZE {
+            break;
+        }
+    }
+
+    log_quota("binaryedge", "after", quota_binaryedge(&client, token).await);
+    info!(" - Get {} proxy from {}, {} credits spent", result.len(), "binaryedge", credits.spent());
+    Ok(result)
+}
+
+/// binaryedge as a source of proxy, found with the protocol of the search filter.
+pub struct Binaryedge {
+    pub query: String,
+    pub page_num: i32,
+    pub token: String,
+    pub budget: Option<u64>,
+    pub protocol: Scheme,
+    pub search_proxy: String,
+}
+
+impl ProxySource for Binaryedge {
+    fn name(&self) -> &str {
+        "binaryedge"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>> {
+        Box::pin(async move {
+            let found = get_socks5_proxy_binaryedge(&self.query, self.page_num, &self.token, self.budget, &self.search_proxy).await?;
+            Ok(with_scheme(found, self.protocol))
+        })
+    }
+}
@@ -0,0 +1,165 @@
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use reqwest;
+use serde_json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::search_api::client::{log_quota, search_client, send_json, Credits};
+use crate::search_api::error::SearchError;
+use crate::search_api::query::censys_service;
+use crate::search_api::source::{with_scheme, ProxySource};
+use crate::utils::proxy::{join_host_port, Proxy, Scheme};
+
+// Censys returns at most 100 hosts per page and bills one query per page
+const PAGE_SIZE: i32 = 100;
+
+fn rate_limited(data: &serde_json::Value) -> bool {
+    data["code"].as_i64() == Some(429)
+}
+
+// censys answers errors such as `{"code": 403, "status": "Forbidden", "error": "..."}`
+fn error_of(data: &serde_json::Value) -> Option<SearchError> {
+    let error = data["error"].as_str()?;
+    Some(SearchError::from_message(
+        error.to_string(),
+        &["unauthorized", "api id", "credentials"],
+        &["quota", "credit"],
+    ))
+}
+
+// services.service_name: SOCKS and services.banner: "No authentication" and location.country_code: CN
+async fn query_censys(
+    client: &reqwest::Client,
+    query: &str,
+    cursor: &str,
+    api_id: &str,
+    secret: &str,
+) -> Result<serde_json::Value, SearchError> {
+    let censys_api_url = String::from("https://search.censys.io/api/v2/hosts/search");
+
+    let mut params = HashMap::new();
+    params.insert("q", query);
+    let per_page = &PAGE_SIZE.to_string()[..];
+    params.insert("per_page", per_page);
+    if !cursor.is_empty() {
+        params.insert("cursor", cursor);
+    }
+
+    let req = client
+        .get(censys_api_url)
+        .query(&params)
+        .basic_auth(api_id, Some(secret));
+    let data = send_json("censys", req, rate_limited).await?;
+    if let Some(e) = error_of(&data) {
+        return Err(e);
+    }
+    if !data["result"]["hits"].is_array() {
+        return Err(SearchError::Malformed(String::from("no hits in the answer of censys")));
+    }
+    Ok(data)
+}
+
+// Queries used out of the allowance of the account
+async fn quota_censys(client: &reqwest::Client, api_id: &str, secret: &str) -> Option<String> {
+    let req = client
+        .get("https://search.censys.io/api/v1/account")
+        .basic_auth(api_id, Some(secret));
+    let data = send_json("censys", req, rate_limited).await.ok()?;
+    match (&data["quota"]["used"], &data["quota"]["allowance"]) {
+        (serde_json::Value::Number(used), serde_json::Value::Number(allowance)) => {
+            Some(format!("{} of {} queries used", used, allowance))
+        }
+        _ => None,
+    }
+}
+
+/// Page through the results of `query` until `size` proxy are found, without
+/// spending more than `budget` queries on this run. Only the services of the
+/// hosts found which speak `protocol` are taken.
+pub async fn get_socks5_proxy_censys(
+    query: &str,
+    size: i32,
+    api_id: &str,
+    secret: &str,
+    protocol: Scheme,
+    budget: Option<u64>,
+    search_proxy: &str,
+) -> Result<Vec<Proxy>, SearchError> {
+    info!("{}", "Searching from censys...");
+    let client = search_client(search_proxy, Duration::from_secs(30))?;
+    log_quota("censys", "before", quota_censys(&client, api_id, secret).await);
+
+    // a negative size asks for nothing rather than for every page
+    let size = size.max(0) as usize;
+    let mut result: Vec<Proxy> = Vec::new();
+    let mut credits = Credits::new("censys", budget);
+    let mut cursor = String::new();
+    while result.len() < size && credits.allows(1) {
+        let data = match query_censys(&client, query, &cursor, api_id, secret).await {
+            Ok(data) => data,
+            Err(e) if result.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("  {}, keeping the {} proxy found so far", e, result.len());
+                break;
+            }
+        };
+        credits.spend(1);
+        let hits = match data["result"]["hits"].as_array() {
+            Some(hits) if !hits.is_empty() => hits,
+            _ => break,
+        };
+        // a host may run several services, only the ports of the protocol are taken
+        let service_name = censys_service(protocol);
+        for i in hits.iter() {
+            let ip = match i["ip"].as_str() {
+                Some(ip) => ip,
+                None => continue,
+            };
+            let services = i["services"].as_array().into_iter().flatten();
+            for service in services.filter(|s| s["service_name"].as_str() == Some(service_name)) {
+                let port = match service["port"].as_u64() {
+                    Some(port) => port,
+                    None => continue,
+                };
+                match Proxy::parse(&join_host_port(ip, port), "censys") {
+                    Ok(proxy) => result.push(proxy),
+                    Err(e) => debug!("  {}", e),
+                }
+            }
+        }
+        match data["result"]["links"]["next"].as_str() {
+            Some(next) if !next.is_empty() => cursor = next.to_string(),
+            _ => break,
+        }
+    }
+    result.truncate(size);
+
+    log_quota("censys", "after", quota_censys(&client, api_id, secret).await);
+    info!(" - Get {} proxy from {}, {} credits spent", result.len(), "censys", credits.spent());
+    Ok(result)
+}
+
+/// censys as a source of proxy, found with the protocol of the search filter.
+pub struct Censys {
+    pub query: String,
+    pub size: i32,
+    pub api_id: String,
+    pub secret: String,
+    pub budget: Option<u64>,
+    pub protocol: Scheme,
+    pub search_proxy: String,
+}
+
+impl ProxySource for Censys {
+    fn name(&self) -> &str {
+        "censys"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>> {
+        Box::pin(async move {
+            let found = get_socks5_proxy_censys(&self.query, self.size, &self.api_id, &self.secret, self.protocol, self.budget, &self.search_proxy).await?;
+            Ok(with_scheme(found, self.protocol))
+        })
+    }
+}
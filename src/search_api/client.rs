@@ -0,0 +1,245 @@
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use reqwest::{RequestBuilder, StatusCode};
+use serde_json;
+
+use crate::search_api::error::SearchError;
+
+// Tries after the first rate-limited answer, waiting twice as long every time
+const RATE_LIMIT_RETRIES: u32 = 4;
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Client of a search engine api, through the `--search-proxy` if set.
+pub fn search_client(search_proxy: &str, time_out: Duration) -> Result<reqwest::Client, SearchError> {
+    let builder = reqwest::Client::builder().timeout(time_out);
+    let client = match search_proxy.is_empty() {
+        true => builder.build()?,
+        false => builder.proxy(reqwest::Proxy::all(search_proxy)?).build()?,
+    };
+    Ok(client)
+}
+
+/// Headers carrying the api key `token` of an engine under `name`. A token which
+/// is not a valid header value can only be a wrong one.
+pub fn api_key_header(name: &'static str, token: &str) -> Result<HeaderMap, SearchError> {
+    let token = HeaderValue::from_str(token)
+        .map_err(|_| SearchError::Auth(String::from("the token is not a valid header value")))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(name, token);
+    Ok(headers)
+}
+
+/// Error message of an error answer, under the key most engines use for it.
+pub fn message_of(data: &serde_json::Value) -> String {
+    ["message", "errmsg", "error", "detail", "status"]
+        .iter()
+        .find_map(|k| data[*k].as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Send `request` and read the JSON answer. While `engine` answers that too many
+/// requests were made, with HTTP 429 or an answer for which `rate_limited` is
+/// true, or while the request times out, wait and send it again. A refused token
+/// or an exhausted quota told by the HTTP status is turned into its error, other
+/// error answers are left to the engine.
+pub async fn send_json(
+    engine: &str,
+    request: RequestBuilder,
+    rate_limited: fn(&serde_json::Value) -> bool,
+) -> Result<serde_json::Value, SearchError> {
+    let mut backoff = RATE_LIMIT_BACKOFF;
+    let mut timed_out = None;
+    for retry in 0..=RATE_LIMIT_RETRIES {
+        if retry > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        let req = request
+            .try_clone()
+            .ok_or_else(|| SearchError::Engine(String::from("request can not be sent again")))?;
+        let r = match req.send().await {
+            Ok(r) => r,
+            Err(e) if e.is_timeout() => {
+                warn!("  {} timed out, trying again in {:?}", engine, backoff);
+                timed_out = Some(e);
+                continue;
+            }
+            Err(e) => return Err(SearchError::Network(e)),
+        };
+        let status = r.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            // the engine knows best how long to wait
+            if let Some(delay) = r
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+            {
+                backoff = Duration::from_secs(delay);
+            }
+            warn!("  {} rate limit reached, waiting {:?}", engine, backoff);
+            timed_out = None;
+            continue;
+        }
+        let body = r.text().await?;
+        let data = serde_json::from_str::<serde_json::Value>(&body)
+            .map_err(|e| SearchError::Malformed(format!("{} answered {}: {}", engine, status, e)))?;
+        if let Some(err) = error_of_status(status, &data) {
+            return Err(err);
+        }
+        if !rate_limited(&data) {
+            return Ok(data);
+        }
+        warn!("  {} rate limit reached, waiting {:?}", engine, backoff);
+    }
+    match timed_out {
+        Some(e) => Err(SearchError::Network(e)),
+        None => Err(SearchError::RateLimited),
+    }
+}
+
+/// Error told by the HTTP `status` of an answer: a refused token or an exhausted
+/// quota, a 403 being either one depending on the message in `data`.
+fn error_of_status(status: StatusCode, data: &serde_json::Value) -> Option<SearchError> {
+    let message = match message_of(data) {
+        m if m.is_empty() => status.to_string(),
+        m => m,
+    };
+    match status {
+        StatusCode::UNAUTHORIZED => Some(SearchError::Auth(message)),
+        StatusCode::PAYMENT_REQUIRED => Some(SearchError::Quota(message)),
+        StatusCode::FORBIDDEN => {
+            let lower = message.to_lowercase();
+            match lower.contains("quota") || lower.contains("credit") {
+                true => Some(SearchError::Quota(message)),
+                false => Some(SearchError::Auth(message)),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Credits one search engine may spend in one run, as set by `--search-budget`,
+/// counted the way the engine bills them: per result or per request.
+#[derive(Debug)]
+pub struct Credits {
+    engine: &'static str,
+    budget: Option<u64>,
+    spent: u64,
+}
+
+impl Credits {
+    pub fn new(engine: &'static str, budget: Option<u64>) -> Self {
+        Credits { engine, budget, spent: 0 }
+    }
+
+    /// Whether a request costing `cost` credits still fits in the budget.
+    pub fn allows(&self, cost: u64) -> bool {
+        match self.budget {
+            Some(budget) if cost == 0 || self.spent + cost > budget => {
+                info!(" - Credit budget of {} reached on {}, {} spent", budget, self.engine, self.spent);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// `wanted` results, or fewer so that they fit in what is left of the budget.
+    pub fn fit(&self, wanted: i32) -> i32 {
+        match self.budget {
+            Some(budget) => wanted.min(budget.saturating_sub(self.spent).min(i32::MAX as u64) as i32),
+            None => wanted,
+        }
+    }
+
+    /// Count `cost` credits as spent, once the engine answered.
+    pub fn spend(&mut self, cost: u64) {
+        self.spent += cost;
+    }
+
+    pub fn spent(&self) -> u64 {
+        self.spent
+    }
+}
+
+/// Log the quota left on the account of `engine`, `when` being before or after searching.
+pub fn log_quota(engine: &str, when: &str, quota: Option<String>) {
+    match quota {
+        Some(quota) => info!(" - Quota of {} {} searching: {}", engine, when, quota),
+        None => debug!("  quota of {} is unknown", engine),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn error_of_status_codes() {
+        let data = json!({"message": "invalid api key"});
+        assert!(matches!(error_of_status(StatusCode::UNAUTHORIZED, &data), Some(SearchError::Auth(m)) if m == "invalid api key"));
+        assert!(matches!(error_of_status(StatusCode::PAYMENT_REQUIRED, &data), Some(SearchError::Quota(_))));
+        assert!(error_of_status(StatusCode::OK, &data).is_none());
+        // other errors are left to the engine
+        assert!(error_of_status(StatusCode::INTERNAL_SERVER_ERROR, &data).is_none());
+        assert!(error_of_status(StatusCode::NOT_FOUND, &json!({})).is_none());
+    }
+
+    #[test]
+    fn error_of_forbidden() {
+        let quota = json!({"error": "Monthly Query Credits exhausted"});
+        assert!(matches!(error_of_status(StatusCode::FORBIDDEN, &quota), Some(SearchError::Quota(_))));
+        let quota = json!({"detail": "quota exceeded"});
+        assert!(matches!(error_of_status(StatusCode::FORBIDDEN, &quota), Some(SearchError::Quota(_))));
+        let auth = json!({"errmsg": "access denied"});
+        assert!(matches!(error_of_status(StatusCode::FORBIDDEN, &auth), Some(SearchError::Auth(_))));
+        // the status stands in for a missing message
+        assert!(matches!(error_of_status(StatusCode::FORBIDDEN, &json!([])), Some(SearchError::Auth(m)) if m == "403 Forbidden"));
+    }
+
+    #[test]
+    fn credits_without_budget() {
+        let mut credits = Credits::new("test", None);
+        credits.spend(1000);
+        assert!(credits.allows(1000));
+        assert!(credits.allows(0));
+        assert_eq!(credits.fit(100), 100);
+        assert_eq!(credits.spent(), 1000);
+    }
+
+    #[test]
+    fn credits_allows_within_budget() {
+        let mut credits = Credits::new("test", Some(100));
+        assert!(credits.allows(100));
+        assert!(!credits.allows(101));
+        credits.spend(60);
+        assert!(credits.allows(40));
+        assert!(!credits.allows(41));
+        // nothing left to ask for counts as the budget being reached
+        assert!(!credits.allows(0));
+        credits.spend(40);
+        assert!(!credits.allows(1));
+        assert_eq!(credits.spent(), 100);
+    }
+
+    #[test]
+    fn credits_fit() {
+        let mut credits = Credits::new("test", Some(100));
+        assert_eq!(credits.fit(50), 50);
+        assert_eq!(credits.fit(500), 100);
+        credits.spend(80);
+        assert_eq!(credits.fit(50), 20);
+        credits.spend(30);
+        // spending past the budget leaves nothing, not a negative size
+        assert_eq!(credits.fit(50), 0);
+        assert!(!credits.allows(credits.fit(50) as u64));
+
+        let credits = Credits::new("test", Some(u64::MAX));
+        assert_eq!(credits.fit(i32::MAX), i32::MAX);
+    }
+}
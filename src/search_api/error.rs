@@ -0,0 +1,79 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Why searching one source failed, each engine telling its own error answers
+/// apart so that a broken source is skipped with a useful message.
+#[derive(Debug)]
+pub enum SearchError {
+    /// The token, key or account was refused
+    Auth(String),
+    /// The account has no query, credit or point left
+    Quota(String),
+    /// The engine kept answering that too many requests were made
+    RateLimited,
+    /// The answer is not the JSON the engine is known to send
+    Malformed(String),
+    /// The engine could not be reached
+    Network(reqwest::Error),
+    /// Any other error reported by the engine
+    Engine(String),
+    /// The source did not finish within its timeout
+    Timeout(Duration),
+    /// A proxy list given by the user could not be read
+    Io(std::io::Error),
+}
+
+impl SearchError {
+    /// Error reported by an engine in `message`, a refused token or an exhausted
+    /// quota being told apart by the words the engine uses for them.
+    pub fn from_message(message: String, auth: &[&str], quota: &[&str]) -> Self {
+        let lower = message.to_lowercase();
+        if auth.iter().any(|k| lower.contains(k)) {
+            SearchError::Auth(message)
+        } else if quota.iter().any(|k| lower.contains(k)) {
+            SearchError::Quota(message)
+        } else {
+            SearchError::Engine(message)
+        }
+    }
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::Auth(message) => write!(f, "authentication failed, {}", message),
+            SearchError::Quota(message) => write!(f, "quota exhausted, {}", message),
+            SearchError::RateLimited => write!(f, "still rate limited after backing off"),
+            SearchError::Malformed(message) => write!(f, "malformed response, {}", message),
+            SearchError::Network(e) => write!(f, "network error, {}", e),
+            SearchError::Engine(message) => write!(f, "{}", message),
+            SearchError::Timeout(time_out) => write!(f, "timed out after {:?}", time_out),
+            SearchError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SearchError::Network(e) => Some(e),
+            SearchError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SearchError {
+    fn from(e: std::io::Error) -> Self {
+        SearchError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for SearchError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.is_decode() {
+            true => SearchError::Malformed(e.to_string()),
+            false => SearchError::Network(e),
+        }
+    }
+}
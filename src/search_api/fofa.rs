@@ -1,83 +1,183 @@
 use base64::{engine::general_purpose, Engine as _};
-use log::{info, error};
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
 use reqwest;
 use serde_json;
 use std::{collections::HashMap, time::Duration};
 
+use crate::search_api::client::{log_quota, message_of, search_client, send_json, Credits};
+use crate::search_api::error::SearchError;
+use crate::search_api::source::{with_scheme, ProxySource};
+use crate::utils::proxy::{Proxy, Scheme};
+
+// Results asked for per page, fofa bills every result
+const PAGE_SIZE: i32 = 1000;
+
+// `[820001] 请求过于频繁` and the like
+fn rate_limited(data: &serde_json::Value) -> bool {
+    let errmsg = data["errmsg"].as_str().unwrap_or_default().to_lowercase();
+    errmsg.contains("频繁") || errmsg.contains("too many")
+}
+
+// fofa answers errors such as `{"error": true, "errmsg": "[-700] 账号无效"}` with HTTP 200
+fn error_of(data: &serde_json::Value) -> Option<SearchError> {
+    if data["error"].as_bool() != Some(true) {
+        return None;
+    }
+    Some(SearchError::from_message(
+        message_of(data),
+        // only the errors of the account, a malformed query may be `invalid` as well
+        &["-700", "-702", "账号无效", "登录", "account invalid"],
+        &["820031", "余额", "不足", "insufficient"],
+    ))
+}
+
 async fn query_fofa(
+    client: &reqwest::Client,
     query: &str,
+    page: i32,
     size: i32,
     email: &str,
     token: &str,
-    search_proxy: &str,
-) -> Result<serde_json::Value, bool> {
+) -> Result<serde_json::Value, SearchError> {
     let fofa_api_url = String::from("https://fofa.info/api/v1/search/all");
 
     let mut params = HashMap::new();
     params.insert("email", email);
     params.insert("key", token);
+    let q_page = &page.to_string()[..];
+    params.insert("page", q_page);
     let q_size = &size.to_string()[..];
     params.insert("size", q_size);
     params.insert("fields", "host");
     let qbase64 = general_purpose::URL_SAFE.encode(query);
     params.insert("qbase64", &qbase64[..]);
 
-    let client = if search_proxy.is_empty() {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
-            .build()
-            .unwrap()
-    } else {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
-            .proxy(reqwest::Proxy::all(search_proxy.to_string()).unwrap())
-            .build()
-            .unwrap()
-    };
+    let req = client.get(fofa_api_url).query(&params);
+    let data = send_json("fofa", req, rate_limited).await?;
+    if let Some(e) = error_of(&data) {
+        return Err(e);
+    }
+    if !data["results"].is_array() {
+        return Err(SearchError::Malformed(String::from("no results in the answer of fofa")));
+    }
+    Ok(data)
+}
 
+// F-points and results left on the account
+async fn quota_fofa(client: &reqwest::Client, email: &str, token: &str) -> Option<String> {
     let req = client
-        .get(fofa_api_url)
-        .query(&params)
-        .send()
-        .await;
-    match req {
-        Ok(r) => {
-            match r.json::<serde_json::Value>().await {
-                Ok(v) => return Ok(v),
-                Err(e) => {
-                    error!("  {:?}", e);
-                    return Err(false)
-                }
-            }
-        },
-        Err(e) => {
-            error!("  {:?}", e);
-            return Err(false)
-        }
+        .get("https://fofa.info/api/v1/info/my")
+        .query(&[("email", email), ("key", token)]);
+    let data = send_json("fofa", req, rate_limited).await.ok()?;
+    match (&data["remain_api_data"], &data["fcoin"]) {
+        (serde_json::Value::Number(n), fcoin) => Some(format!("{} results, {} F-points left", n, fcoin)),
+        (_, serde_json::Value::Number(fcoin)) => Some(format!("{} F-points left", fcoin)),
+        _ => None,
     }
 }
 
+/// Page through the results of `query` until `size` of them are fetched, without
+/// spending more than `budget` results on this run.
 pub async fn get_socks5_proxy_fofa(
     query: &str,
     size: i32,
     email: &str,
     token: &str,
+    budget: Option<u64>,
     search_proxy: &str,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+) -> Result<Vec<Proxy>, SearchError> {
     info!("{}", "Searching from fofa...");
-    let mut result: Vec<String> = Vec::new();
-    match query_fofa(query, size, email, token, search_proxy).await {
-        Ok(data) => {
-            for i in data["results"].as_array().unwrap().iter() {
-                let ip_port = &i.to_string()[..];
-                let len = ip_port.len();
-                result.push(ip_port[1..len - 1].to_string());
+    let client = search_client(search_proxy, Duration::from_secs(10))?;
+    log_quota("fofa", "before", quota_fofa(&client, email, token).await);
+
+    let mut result: Vec<Proxy> = Vec::new();
+    let mut credits = Credits::new("fofa", budget);
+    // the offset of a page is counted in pages, so every page has the same size
+    let page_size = credits.fit(size.clamp(1, PAGE_SIZE));
+    let mut fetched = 0;
+    let mut page = 1;
+    while fetched < size && credits.allows(page_size.max(0) as u64) {
+        let data = match query_fofa(&client, query, page, page_size, email, token).await {
+            Ok(data) => data,
+            Err(e) if result.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("  {}, keeping the {} proxy found so far", e, result.len());
+                break;
             }
-        },
-        Err(_) => {
-            return Ok(result);
+        };
+        let results = data["results"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for i in results.iter() {
+            let ip_port = match i.as_str() {
+                Some(ip_port) => ip_port,
+                None => continue,
+            };
+            match Proxy::parse(ip_port, "fofa") {
+                Ok(proxy) => result.push(proxy),
+                Err(e) => debug!("  {}", e),
+            }
+        }
+        credits.spend(results.len() as u64);
+        fetched += results.len() as i32;
+        if results.len() < page_size as usize {
+            break;
         }
+        page += 1;
     }
-    info!(" - Get {} proxy from {}", result.len(), "fofa");
+    result.truncate(size.max(0) as usize);
+
+    log_quota("fofa", "after", quota_fofa(&client, email, token).await);
+    info!(" - Get {} proxy from {}, {} credits spent", result.len(), "fofa", credits.spent());
     Ok(result)
 }
+
+/// fofa as a source of proxy, found with the protocol of the search filter.
+pub struct Fofa {
+    pub query: String,
+    pub size: i32,
+    pub email: String,
+    pub token: String,
+    pub budget: Option<u64>,
+    pub protocol: Scheme,
+    pub search_proxy: String,
+}
+
+impl ProxySource for Fofa {
+    fn name(&self) -> &str {
+        "fofa"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>> {
+        Box::pin(async move {
+            let found = get_socks5_proxy_fofa(&self.query, self.size, &self.email, &self.token, self.budget, &self.search_proxy).await?;
+            Ok(with_scheme(found, self.protocol))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn error_of_refused_account() {
+        let data = json!({"error": true, "errmsg": "[-700] 账号无效"});
+        assert!(matches!(error_of(&data), Some(SearchError::Auth(_))));
+        let data = json!({"error": true, "errmsg": "[-700] Account Invalid"});
+        assert!(matches!(error_of(&data), Some(SearchError::Auth(_))));
+    }
+
+    #[test]
+    fn error_of_invalid_query_is_not_auth() {
+        let data = json!({"error": true, "errmsg": "[820000] query syntax invalid"});
+        assert!(matches!(error_of(&data), Some(SearchError::Engine(_))));
+    }
+
+    #[test]
+    fn error_of_exhausted_quota() {
+        let data = json!({"error": true, "errmsg": "[820031] F点余额不足"});
+        assert!(matches!(error_of(&data), Some(SearchError::Quota(_))));
+        assert!(error_of(&json!({"error": false, "results": []})).is_none());
+    }
+}
@@ -0,0 +1,155 @@
+use base64::{engine::general_purpose, Engine as _};
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use reqwest;
+use serde_json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::search_api::client::{log_quota, message_of, search_client, send_json, Credits};
+use crate::search_api::error::SearchError;
+use crate::search_api::source::{with_scheme, ProxySource};
+use crate::utils::proxy::{join_host_port, Proxy, Scheme};
+
+// Hunter returns at most 100 assets per page and bills one point per asset
+const PAGE_SIZE: i32 = 100;
+// Hunter answers code 429 when requests come faster than about one per 2 seconds
+const PAGE_DELAY: Duration = Duration::from_secs(3);
+
+fn rate_limited(data: &serde_json::Value) -> bool {
+    data["code"].as_i64() == Some(429)
+}
+
+// hunter answers errors with a code other than 200 and HTTP 200
+fn error_of(data: &serde_json::Value) -> Option<SearchError> {
+    if data["code"].as_i64() == Some(200) {
+        return None;
+    }
+    Some(SearchError::from_message(
+        format!("{}: {}", data["code"], message_of(data)),
+        &["401", "令牌", "api-key"],
+        &["40204", "积分", "quota"],
+    ))
+}
+
+// protocol="socks5"&&ip.country="CN"
+async fn query_hunter(
+    client: &reqwest::Client,
+    query: &str,
+    page_num: i32,
+    page_size: i32,
+    token: &str,
+    after: Option<&str>,
+) -> Result<serde_json::Value, SearchError> {
+    let hunter_api_url = String::from("https://hunter.qianxin.com/openApi/search");
+
+    let mut params = HashMap::new();
+    params.insert("api-key", token);
+    let search = general_purpose::URL_SAFE.encode(query);
+    params.insert("search", &search[..]);
+    let param_num_str = &page_num.to_string()[..];
+    params.insert("page", param_num_str);
+    let page_size = &page_size.to_string()[..];
+    params.insert("page_size", page_size);
+    // 3 for every asset, web or not
+    params.insert("is_web", "3");
+    if let Some(after) = after {
+        params.insert("start_time", after);
+    }
+
+    let req = client.get(hunter_api_url).query(&params);
+    let data = send_json("hunter", req, rate_limited).await?;
+    if let Some(e) = error_of(&data) {
+        return Err(e);
+    }
+    // no assets at all comes as `"arr": null`
+    if !data["data"].is_object() {
+        return Err(SearchError::Malformed(String::from("no data in the answer of hunter")));
+    }
+    Ok(data)
+}
+
+/// `after` limits the search to the assets seen since that day, as `YYYY-MM-DD`,
+/// hunter taking it beside the query. Results are paged through until `size` of
+/// them are fetched, without spending more than `budget` points on this run.
+pub async fn get_socks5_proxy_hunter(
+    query: &str,
+    size: i32,
+    token: &str,
+    after: Option<&str>,
+    budget: Option<u64>,
+    search_proxy: &str,
+) -> Result<Vec<Proxy>, SearchError> {
+    info!("{}", "Searching from hunter...");
+    let client = search_client(search_proxy, Duration::from_secs(30))?;
+    // hunter has no account api, every answer tells the points left instead
+    let mut quota = None;
+
+    let mut result: Vec<Proxy> = Vec::new();
+    let mut credits = Credits::new("hunter", budget);
+    // the offset of a page is counted in pages, so every page has the same size
+    let page_size = credits.fit(size.clamp(1, PAGE_SIZE));
+    let mut fetched = 0;
+    let mut page = 1;
+    while fetched < size && credits.allows(page_size.max(0) as u64) {
+        if page > 1 {
+            // stay under the rate limit instead of running into it
+            tokio::time::sleep(PAGE_DELAY).await;
+        }
+        let data = match query_hunter(&client, query, page, page_size, token, after).await {
+            Ok(data) => data,
+            Err(e) if result.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("  {}, keeping the {} proxy found so far", e, result.len());
+                break;
+            }
+        };
+        quota = data["data"]["rest_quota"].as_str().map(|q| q.to_string()).or(quota);
+        let assets = data["data"]["arr"].as_array().map(Vec::as_slice).unwrap_or_default();
+        credits.spend(assets.len() as u64);
+        fetched += assets.len() as i32;
+        for i in assets.iter() {
+            let (ip, port) = match (i["ip"].as_str(), i["port"].as_u64()) {
+                (Some(ip), Some(port)) => (ip, port),
+                _ => continue,
+            };
+            match Proxy::parse(&join_host_port(ip, port), "hunter") {
+                Ok(proxy) => result.push(proxy),
+                Err(e) => debug!("  {}", e),
+            }
+        }
+        if assets.len() < page_size as usize {
+            break;
+        }
+        page += 1;
+    }
+    result.truncate(size.max(0) as usize);
+
+    log_quota("hunter", "after", quota);
+    info!(" - Get {} proxy from {}, {} credits spent", result.len(), "hunter", credits.spent());
+    Ok(result)
+}
+
+/// hunter as a source of proxy, found with the protocol of the search filter.
+pub struct Hunter {
+    pub query: String,
+    pub size: i32,
+    pub token: String,
+    pub after: Option<String>,
+    pub budget: Option<u64>,
+    pub protocol: Scheme,
+    pub search_proxy: String,
+}
+
+impl ProxySource for Hunter {
+    fn name(&self) -> &str {
+        "hunter"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>> {
+        Box::pin(async move {
+            let found = get_socks5_proxy_hunter(&self.query, self.size, &self.token, self.after.as_deref(), self.budget, &self.search_proxy).await?;
+            Ok(with_scheme(found, self.protocol))
+        })
+    }
+}
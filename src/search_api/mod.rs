@@ -1,4 +1,13 @@
+pub mod binaryedge;
+pub mod censys;
+pub mod client;
+pub mod error;
 pub mod fofa;
 pub mod free_api;
+pub mod hunter;
+pub mod proxy_list;
 pub mod quake;
+pub mod query;
+pub mod source;
+pub mod shodan;
 pub mod zoomeye;
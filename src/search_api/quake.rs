@@ -1,90 +1,160 @@
 use std::time::Duration;
 
-use log::{info, error};
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
 use reqwest;
-use reqwest::header::HeaderMap;
 use serde_json;
 
+use crate::search_api::client::{api_key_header, log_quota, message_of, search_client, send_json, Credits};
+use crate::search_api::error::SearchError;
+use crate::search_api::source::{with_scheme, ProxySource};
+use crate::utils::proxy::{join_host_port, Proxy, Scheme};
+
+// Results asked for per request, quake bills every result
+const PAGE_SIZE: i32 = 100;
+
+// q3005 stands for requests coming too fast
+fn rate_limited(data: &serde_json::Value) -> bool {
+    data["code"].as_str() == Some("q3005")
+}
+
+// quake answers errors with a code other than 0, such as `{"code": "u3011", "message": "..."}`
+fn error_of(data: &serde_json::Value) -> Option<SearchError> {
+    if data["code"] == serde_json::json!(0) {
+        return None;
+    }
+    Some(SearchError::from_message(
+        format!("{}: {}", data["code"], message_of(data)),
+        &["u3004", "u3011", "token", "认证"],
+        &["q3015", "u3005", "积分", "credit"],
+    ))
+}
+
 async fn query_quake(
+    client: &reqwest::Client,
     query: &str,
+    start: i32,
     size: i32,
     token: &str,
-    search_proxy: &str,
-) -> Result<serde_json::value::Value, bool> {
+    after: Option<&str>,
+) -> Result<serde_json::value::Value, SearchError> {
     let quake_api_url = String::from("https://quake.360.net/api/v3/search/quake_service");
 
+    let q_start = &start.to_string()[..];
     let q_size = &size.to_string()[..];
-    let data = serde_json::json!({
+    let mut data = serde_json::json!({
         "query": query,
-        "start": "0",
+        "start": q_start,
         "size": q_size,
     });
-
-    let mut headers = HeaderMap::new();
-    headers.insert("X-QuakeToken", token.parse().unwrap());
-    headers.insert("Content-Type", "application/json".parse().unwrap());
-
-    let client = if search_proxy.is_empty() {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
-            .build()
-            .unwrap()
-    } else {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
-            .proxy(reqwest::Proxy::all(search_proxy.to_string()).unwrap())
-            .build()
-            .unwrap()
-    };
+    if let Some(after) = after {
+        data["start_time"] = serde_json::json!(format!("{} 00:00:00", after));
+    }
 
     let req = client
         .post(quake_api_url)
-        .headers(headers)
-        .body(data.to_string())
-        .send()
-        .await;
-    match req {
-        Ok(r) => {
-            match r.json::<serde_json::Value>().await {
-                Ok(v) => return Ok(v),
-                Err(e) => {
-                    error!("  {:?}", e);
-                    return Err(false)
-                }
-            }
-        },
-        Err(e) => {
-            error!("  {:?}", e);
-            return Err(false)
-        }
+        .headers(api_key_header("X-QuakeToken", token)?)
+        .json(&data);
+    let data = send_json("quake", req, rate_limited).await?;
+    if let Some(e) = error_of(&data) {
+        return Err(e);
+    }
+    if !data["data"].is_array() {
+        return Err(SearchError::Malformed(String::from("no data in the answer of quake")));
+    }
+    Ok(data)
+}
+
+// Credits left on the account this month, and the ones which never expire
+async fn quota_quake(client: &reqwest::Client, token: &str) -> Option<String> {
+    let req = client
+        .get("https://quake.360.net/api/v3/user/info")
+        .headers(api_key_header("X-QuakeToken", token).ok()?);
+    let data = send_json("quake", req, rate_limited).await.ok()?;
+    match &data["data"]["month_remaining_credit"] {
+        serde_json::Value::Number(n) => Some(format!(
+            "{} monthly credits, {} constant credits left",
+            n,
+            data["data"]["constant_credit"].as_i64().unwrap_or_default()
+        )),
+        _ => None,
     }
 }
 
+/// `after` limits the search to the services seen since that day, as `YYYY-MM-DD`,
+/// quake taking it beside the query. Results are paged through until `size` of
+/// them are fetched, without spending more than `budget` results on this run.
 pub async fn get_socks5_proxy_quake(
     query: &str,
     size: i32,
     token: &str,
+    after: Option<&str>,
+    budget: Option<u64>,
     search_proxy: &str,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+) -> Result<Vec<Proxy>, SearchError> {
     info!("{}", "Searching from quake...");
-    let mut result: Vec<String> = Vec::new();
-    match query_quake(query, size, token, search_proxy).await {
-        Ok(data) => {
-            for i in data["data"].as_array().unwrap().iter() {
-                let mut ip_port = String::from("");
-                let ip = i["ip"].to_string();
-                let port = i["port"].to_string();
-                let iplen = ip.len();
-                ip_port += &ip.to_string()[1..iplen - 1];
-                ip_port += ":";
-                ip_port += &port;
-                result.push(ip_port.to_string());
+    let client = search_client(search_proxy, Duration::from_secs(10))?;
+    log_quota("quake", "before", quota_quake(&client, token).await);
+
+    let mut result: Vec<Proxy> = Vec::new();
+    let mut credits = Credits::new("quake", budget);
+    let mut fetched = 0;
+    while fetched < size {
+        let page_size = credits.fit(PAGE_SIZE.min(size - fetched));
+        if !credits.allows(page_size.max(0) as u64) {
+            break;
+        }
+        let data = match query_quake(&client, query, fetched, page_size, token, after).await {
+            Ok(data) => data,
+            Err(e) if result.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("  {}, keeping the {} proxy found so far", e, result.len());
+                break;
+            }
+        };
+        let services = data["data"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for i in services.iter() {
+            let (ip, port) = match (i["ip"].as_str(), i["port"].as_u64()) {
+                (Some(ip), Some(port)) => (ip, port),
+                _ => continue,
+            };
+            match Proxy::parse(&join_host_port(ip, port), "quake") {
+                Ok(proxy) => result.push(proxy),
+                Err(e) => debug!("  {}", e),
             }
-        },
-        Err(_) => {
-            return Ok(result);
+        }
+        credits.spend(services.len() as u64);
+        fetched += services.len() as i32;
+        if services.len() < page_size as usize {
+            break;
         }
     }
-    info!(" - Get {} proxy from {}", result.len(), "quake");
+
+    log_quota("quake", "after", quota_quake(&client, token).await);
+    info!(" - Get {} proxy from {}, {} credits spent", result.len(), "quake", credits.spent());
     Ok(result)
 }
+
+/// quake as a source of proxy, found with the protocol of the search filter.
+pub struct Quake {
+    pub query: String,
+    pub size: i32,
+    pub token: String,
+    pub after: Option<String>,
+    pub budget: Option<u64>,
+    pub protocol: Scheme,
+    pub search_proxy: String,
+}
+
+impl ProxySource for Quake {
+    fn name(&self) -> &str {
+        "quake"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>> {
+        Box::pin(async move {
+            let found = get_socks5_proxy_quake(&self.query, self.size, &self.token, self.after.as_deref(), self.budget, &self.search_proxy).await?;
+            Ok(with_scheme(found, self.protocol))
+        })
+    }
+}
@@ -0,0 +1,241 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use crate::utils::proxy::Scheme;
+
+/// Search engines which are given a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Fofa,
+    Zoomeye,
+    Quake,
+    Shodan,
+    Censys,
+    Hunter,
+    Binaryedge,
+}
+
+/// What to look for on every search engine, such as "socks5, country=US, seen in
+/// last 7 days", turned into the query language of each engine.
+#[derive(Debug, Clone)]
+pub struct SearchFilter {
+    pub protocol: Scheme,
+    /// Country code such as `US`, any country when not set
+    pub country: Option<String>,
+    /// Only hosts seen since this day, as `YYYY-MM-DD`
+    pub after: Option<String>,
+}
+
+impl SearchFilter {
+    pub fn new(protocol: Scheme, country: Option<String>, seen: Option<Duration>) -> Self {
+        SearchFilter {
+            protocol,
+            country: country.map(|c| c.to_uppercase()),
+            after: seen.map(|seen| format_date(SystemTime::now() - seen)),
+        }
+    }
+
+    /// Query for `engine`, either `template` with its `{country}`, `{protocol}` and
+    /// `{after}` placeholders filled in, or the default query of the engine.
+    pub fn query(&self, engine: Engine, template: Option<&str>) -> String {
+        let query = match template {
+            Some(template) => self.render(template),
+            None => self.default_query(engine),
+        };
+        debug!("{:?} query: {}", engine, query);
+        query
+    }
+
+    // Name of the protocol for search engines, which do not tell socks4a from socks4
+    fn protocol_name(&self) -> &'static str {
+        match self.protocol {
+            Scheme::Socks4a => Scheme::Socks4.as_str(),
+            protocol => protocol.as_str(),
+        }
+    }
+
+    fn render(&self, template: &str) -> String {
+        let mut query = template.replace("{protocol}", self.protocol_name());
+        for (placeholder, value) in [("{country}", &self.country), ("{after}", &self.after)] {
+            if !query.contains(placeholder) {
+                continue;
+            }
+            if value.is_none() {
+                warn!("{} is used in the query `{}` but not set, see --search-country and --search-seen", placeholder, template);
+            }
+            query = query.replace(placeholder, value.as_deref().unwrap_or_default());
+        }
+        query
+    }
+
+    // Quake and hunter take the date as a parameter of the api, beside the query,
+    // and binaryedge can not filter on it at all
+    fn default_query(&self, engine: Engine) -> String {
+        let protocol = self.protocol_name();
+        let no_auth = self.protocol == Scheme::Socks5;
+        let mut clauses: Vec<String> = Vec::new();
+        let separator = match engine {
+            Engine::Fofa => {
+                clauses.push(format!("protocol=\"{}\"", protocol));
+                if no_auth {
+                    clauses.push("\"Version:5 Method:No Authentication(0x00)\"".to_string());
+                }
+                clauses.extend(self.country.iter().map(|c| format!("country=\"{}\"", c)));
+                clauses.extend(self.after.iter().map(|a| format!("after=\"{}\"", a)));
+                " && "
+            }
+            Engine::Zoomeye => {
+                clauses.push(format!("service:\"{}\"", protocol));
+                if no_auth {
+                    clauses.push("+banner:\"Version:5 Method:No Authentication(0x00)\"".to_string());
+                }
+                clauses.extend(self.country.iter().map(|c| format!("+country:\"{}\"", c)));
+                clauses.extend(self.after.iter().map(|a| format!("+after:\"{}\"", a)));
+                " "
+            }
+            Engine::Quake => {
+                clauses.push(format!("service:\"{}\"", protocol));
+                if no_auth {
+                    clauses.push("response:\"Version: 5 Accepted Auth Method: 0x0 (No authentication)\"".to_string());
+                }
+                clauses.extend(self.country.iter().map(|c| format!("country:\"{}\"", c)));
+                " AND "
+            }
+            Engine::Shodan => {
+                match no_auth {
+                    true => clauses.push("\"Version: 5\" \"Method: No authentication\"".to_string()),
+                    false => clauses.push(format!("\"{}\"", protocol)),
+                }
+                clauses.extend(self.country.iter().map(|c| format!("country:\"{}\"", c)));
+                // shodan wants dd/mm/yyyy
+                clauses.extend(self.after.iter().map(|a| {
+                    let date: Vec<&str> = a.split('-').rev().collect();
+                    format!("after:\"{}\"", date.join("/"))
+                }));
+                " "
+            }
+            Engine::Censys => {
+                clauses.push(format!("services.service_name: {}", censys_service(self.protocol)));
+                if no_auth {
+                    clauses.push("services.banner: \"No authentication\"".to_string());
+                }
+                clauses.extend(self.country.iter().map(|c| format!("location.country_code: {}", c)));
+                clauses.extend(self.after.iter().map(|a| format!("last_updated_at: [{} TO *]", a)));
+                " and "
+            }
+            Engine::Hunter => {
+                clauses.push(format!("protocol=\"{}\"", protocol));
+                clauses.extend(self.country.iter().map(|c| format!("ip.country=\"{}\"", c)));
+                "&&"
+            }
+            Engine::Binaryedge => {
+                clauses.push(format!("type:\"{}\"", protocol));
+                clauses.extend(self.country.iter().map(|c| format!("country:\"{}\"", c)));
+                " "
+            }
+        };
+        clauses.join(separator)
+    }
+}
+
+/// Name censys gives to the services speaking `protocol`, it does not tell socks versions apart.
+pub fn censys_service(protocol: Scheme) -> &'static str {
+    match protocol {
+        Scheme::Http => "HTTP",
+        _ => "SOCKS",
+    }
+}
+
+// `YYYY-MM-DD` of `time` in UTC, see http://howardhinnant.github.io/date_algorithms.html
+fn format_date(time: SystemTime) -> String {
+    let days = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64 / 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(protocol: Scheme, country: Option<&str>, after: Option<&str>) -> SearchFilter {
+        SearchFilter {
+            protocol,
+            country: country.map(String::from),
+            after: after.map(String::from),
+        }
+    }
+
+    #[test]
+    fn format_known_dates() {
+        let at = |secs: u64| format_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01");
+        assert_eq!(at(946684799), "1999-12-31");
+        assert_eq!(at(951868799), "2000-02-29");
+        assert_eq!(at(1735689599), "2024-12-31");
+        assert_eq!(at(4107628799), "2100-03-01");
+    }
+
+    #[test]
+    fn new_filter_takes_the_day_seen_since() {
+        let f = SearchFilter::new(Scheme::Socks5, Some("us".to_string()), Some(Duration::ZERO));
+        assert_eq!(f.country.as_deref(), Some("US"));
+        assert_eq!(f.after.map(|after| after.len()), Some("YYYY-MM-DD".len()));
+    }
+
+    #[test]
+    fn default_query_of_every_engine() {
+        let socks5 = filter(Scheme::Socks5, Some("US"), Some("2024-01-31"));
+        assert_eq!(
+            socks5.query(Engine::Fofa, None),
+            "protocol=\"socks5\" && \"Version:5 Method:No Authentication(0x00)\" && country=\"US\" && after=\"2024-01-31\""
+        );
+        assert_eq!(
+            socks5.query(Engine::Zoomeye, None),
+            "service:\"socks5\" +banner:\"Version:5 Method:No Authentication(0x00)\" +country:\"US\" +after:\"2024-01-31\""
+        );
+        assert_eq!(
+            socks5.query(Engine::Quake, None),
+            "service:\"socks5\" AND response:\"Version: 5 Accepted Auth Method: 0x0 (No authentication)\" AND country:\"US\""
+        );
+        assert_eq!(
+            socks5.query(Engine::Shodan, None),
+            "\"Version: 5\" \"Method: No authentication\" country:\"US\" after:\"31/01/2024\""
+        );
+        assert_eq!(
+            socks5.query(Engine::Censys, None),
+            "services.service_name: SOCKS and services.banner: \"No authentication\" and location.country_code: US and last_updated_at: [2024-01-31 TO *]"
+        );
+        assert_eq!(socks5.query(Engine::Hunter, None), "protocol=\"socks5\"&&ip.country=\"US\"");
+        assert_eq!(socks5.query(Engine::Binaryedge, None), "type:\"socks5\" country:\"US\"");
+    }
+
+    #[test]
+    fn default_query_of_other_protocols() {
+        let http = filter(Scheme::Http, None, None);
+        assert_eq!(http.query(Engine::Fofa, None), "protocol=\"http\"");
+        assert_eq!(http.query(Engine::Shodan, None), "\"http\"");
+        assert_eq!(http.query(Engine::Censys, None), "services.service_name: HTTP");
+        // engines do not tell socks4a from socks4
+        let socks4a = filter(Scheme::Socks4a, None, None);
+        assert_eq!(socks4a.query(Engine::Zoomeye, None), "service:\"socks4\"");
+        assert_eq!(socks4a.query(Engine::Censys, None), "services.service_name: SOCKS");
+    }
+
+    #[test]
+    fn template_placeholders() {
+        let f = filter(Scheme::Socks4a, Some("DE"), None);
+        assert_eq!(
+            f.query(Engine::Fofa, Some("protocol=\"{protocol}\" && country=\"{country}\" && after=\"{after}\"")),
+            "protocol=\"socks4\" && country=\"DE\" && after=\"\""
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use reqwest;
+use serde_json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::search_api::client::{log_quota, search_client, send_json, Credits};
+use crate::search_api::error::SearchError;
+use crate::search_api::source::{with_scheme, ProxySource};
+use crate::utils::proxy::{join_host_port, Proxy, Scheme};
+
+// Shodan returns 100 matches per page and bills one query credit per page
+const PAGE_SIZE: i32 = 100;
+
+fn rate_limited(data: &serde_json::Value) -> bool {
+    data["error"].as_str().unwrap_or_default().to_lowercase().contains("rate limit")
+}
+
+// shodan answers errors such as `{"error": "Insufficient query credits"}`
+fn error_of(data: &serde_json::Value) -> Option<SearchError> {
+    let error = data["error"].as_str()?;
+    Some(SearchError::from_message(
+        error.to_string(),
+        &["api key", "access denied"],
+        &["credits", "upgrade your api plan"],
+    ))
+}
+
+// "Version: 5" "Method: No authentication" country:"CN"
+async fn query_shodan(
+    client: &reqwest::Client,
+    query: &str,
+    page_num: i32,
+    token: &str,
+) -> Result<serde_json::Value, SearchError> {
+    let shodan_api_url = String::from("https://api.shodan.io/shodan/host/search");
+
+    let mut params = HashMap::new();
+    params.insert("key", token);
+    params.insert("query", query);
+    let param_num_str = &page_num.to_string()[..];
+    params.insert("page", param_num_str);
+    params.insert("minify", "true");
+
+    let req = client.get(shodan_api_url).query(&params);
+    let data = send_json("shodan", req, rate_limited).await?;
+    if let Some(e) = error_of(&data) {
+        return Err(e);
+    }
+    if !data["matches"].is_array() {
+        return Err(SearchError::Malformed(String::from("no matches in the answer of shodan")));
+    }
+    Ok(data)
+}
+
+// Query credits left on the account this month
+async fn quota_shodan(client: &reqwest::Client, token: &str) -> Option<String> {
+    let req = client
+        .get("https://api.shodan.io/api-info")
+        .query(&[("key", token)]);
+    let data = send_json("shodan", req, rate_limited).await.ok()?;
+    match &data["query_credits"] {
+        serde_json::Value::Number(n) => Some(format!("{} query credits left", n)),
+        _ => None,
+    }
+}
+
+/// Page through the results of `query` until `size` of them are fetched, without
+/// spending more than `budget` query credits on this run.
+pub async fn get_socks5_proxy_shodan(
+    query: &str,
+    size: i32,
+    token: &str,
+    budget: Option<u64>,
+    search_proxy: &str,
+) -> Result<Vec<Proxy>, SearchError> {
+    info!("{}", "Searching from shodan...");
+    let client = search_client(search_proxy, Duration::from_secs(30))?;
+    log_quota("shodan", "before", quota_shodan(&client, token).await);
+
+    let mut result: Vec<Proxy> = Vec::new();
+    let mut credits = Credits::new("shodan", budget);
+    let page_num = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    for page in 1..page_num + 1 {
+        if !credits.allows(1) {
+            break;
+        }
+        let data = match query_shodan(&client, query, page, token).await {
+            Ok(data) => data,
+            Err(e) if result.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("  {}, keeping the {} proxy found so far", e, result.len());
+                break;
+            }
+        };
+        credits.spend(1);
+        let matches = data["matches"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for i in matches.iter() {
+            let (ip, port) = match (i["ip_str"].as_str(), i["port"].as_u64()) {
+                (Some(ip), Some(port)) => (ip, port),
+                _ => continue,
+            };
+            match Proxy::parse(&join_host_port(ip, port), "shodan") {
+                Ok(proxy) => result.push(proxy),
+                Err(e) => debug!("  {}", e),
+            }
+        }
+        if matches.len() < PAGE_SIZE as usize {
+            break;
+        }
+    }
+    result.truncate(size.max(0) as usize);
+
+    log_quota("shodan", "after", quota_shodan(&client, token).await);
+    info!(" - Get {} proxy from {}, {} credits spent", result.len(), "shodan", credits.spent());
+    Ok(result)
+}
+
+/// shodan as a source of proxy, found with the protocol of the search filter.
+pub struct Shodan {
+    pub query: String,
+    pub size: i32,
+    pub token: String,
+    pub budget: Option<u64>,
+    pub protocol: Scheme,
+    pub search_proxy: String,
+}
+
+impl ProxySource for Shodan {
+    fn name(&self) -> &str {
+        "shodan"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>> {
+        Box::pin(async move {
+            let found = get_socks5_proxy_shodan(&self.query, self.size, &self.token, self.budget, &self.search_proxy).await?;
+            Ok(with_scheme(found, self.protocol))
+        })
+    }
+}
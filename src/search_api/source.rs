@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::{join_all, BoxFuture};
+use log::{error, info};
+use tokio::time::timeout;
+
+use crate::search_api::error::SearchError;
+use crate::utils::proxy::{Proxy, Scheme};
+
+/// Where proxy are found: a free site, a search engine or a list given by the user.
+pub trait ProxySource: Send + Sync {
+    /// Name of the source, the same as the `source` of the proxy it finds
+    fn name(&self) -> &str;
+
+    /// Every proxy the source knows about, not checked yet
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>>;
+}
+
+/// What one source gave on the last run.
+#[derive(Debug, Clone, Default)]
+pub struct SourceStats {
+    /// Proxy fetched from the source
+    pub fetched: usize,
+    /// Fetched proxy neither known yet nor found by a source registered before
+    pub unique: usize,
+    /// Unique proxy which passed the availability check
+    pub validated: usize,
+    pub elapsed: Duration,
+    /// Why the source failed, if it did
+    pub error: Option<String>,
+}
+
+/// Every enabled source, run all at once with a timeout for each of them so
+/// that a slow source only loses its own proxy.
+pub struct SourceRegistry {
+    sources: Vec<Box<dyn ProxySource>>,
+    timeout: Duration,
+    stats: Mutex<Vec<(String, SourceStats)>>,
+}
+
+impl SourceRegistry {
+    pub fn new(timeout: Duration) -> Self {
+        SourceRegistry {
+            sources: Vec::new(),
+            timeout,
+            stats: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn register(&mut self, source: Box<dyn ProxySource>) -> &mut Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Fetch from every source at once. Proxy already in `known` or found by a
+    /// source registered before are dropped, the others are added to `known`.
+    pub async fn fetch_all(&self, known: &mut HashSet<String>) -> Vec<Proxy> {
+        let runs = self.sources.iter().map(|source| async move {
+            let start = Instant::now();
+            let found = match timeout(self.timeout, source.fetch()).await {
+                Ok(found) => found,
+                Err(_) => Err(SearchError::Timeout(self.timeout)),
+            };
+            (found, start.elapsed())
+        });
+        let results = join_all(runs).await;
+
+        // deduplicated in the order of registration, whichever source answered first
+        let mut r: Vec<Proxy> = Vec::new();
+        let mut stats = Vec::new();
+        for (source, (found, elapsed)) in self.sources.iter().zip(results) {
+            let mut s = SourceStats { elapsed, ..Default::default() };
+            match found {
+                Ok(found) => {
+                    s.fetched = found.len();
+                    for proxy in found {
+                        if known.insert(proxy.key()) {
+                            s.unique += 1;
+                            r.push(proxy);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Searching from {} failed, {}", source.name(), e);
+                    s.error = Some(e.to_string());
+                }
+            }
+            stats.push((source.name().to_string(), s));
+        }
+        *self.stats.lock().unwrap() = stats;
+        r
+    }
+
+    /// Count the proxy of the last run which passed the availability check, by source.
+    pub fn record_validated(&self, available: &[Proxy]) {
+        let mut stats = self.stats.lock().unwrap();
+        for proxy in available {
+            if let Some((_, s)) = stats.iter_mut().find(|(name, _)| *name == proxy.source) {
+                s.validated += 1;
+            }
+        }
+    }
+
+    pub fn log_stats(&self) {
+        for (name, s) in self.stats.lock().unwrap().iter() {
+            match &s.error {
+                Some(e) => info!(" - {}: failed after {:.1?}, {}", name, s.elapsed, e),
+                None => info!(
+                    " - {}: fetched {}, unique {}, validated {} in {:.1?}",
+                    name, s.fetched, s.unique, s.validated, s.elapsed
+                ),
+            }
+        }
+    }
+}
+
+/// The search engines only give `ip:port`, which are found with the protocol of the search filter.
+pub fn with_scheme(found: Vec<Proxy>, scheme: Scheme) -> Vec<Proxy> {
+    found
+        .into_iter()
+        .map(|mut proxy| {
+            proxy.scheme = scheme;
+            proxy
+        })
+        .collect()
+}
@@ -1,17 +1,39 @@
-use log::{info, error};
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
 use reqwest;
-use reqwest::header::HeaderMap;
 use serde_json;
 use std::collections::HashMap;
 use std::time::Duration;
 
+use crate::search_api::client::{api_key_header, log_quota, message_of, search_client, send_json, Credits};
+use crate::search_api::error::SearchError;
+use crate::search_api::source::{with_scheme, ProxySource};
+use crate::utils::proxy::{join_host_port, Proxy, Scheme};
+
+// Zoomeye returns 20 results per page and bills every result
+const PAGE_SIZE: usize = 20;
+
+fn rate_limited(data: &serde_json::Value) -> bool {
+    data["error"].as_str() == Some("request_frequency_limit")
+}
+
+// zoomeye answers errors such as `{"error": "login_required", "message": "..."}`
+fn error_of(data: &serde_json::Value) -> Option<SearchError> {
+    let error = data["error"].as_str()?;
+    Some(SearchError::from_message(
+        format!("{}: {}", error, message_of(data)),
+        &["login_required", "invalid_access_token", "invalid_api_key", "unauthorized"],
+        &["credits_insufficent", "credits_insufficient", "resource_insufficient"],
+    ))
+}
+
 // service:"socks5" +after:"2023-02-01" +banner:"Version:5 Method:No Authentication(0x00)" +country:"CN"
 async fn query_zoomeye(
+    client: &reqwest::Client,
     query: &str,
     page_num: i32,
     token: &str,
-    search_proxy: &str,
-) -> Result<serde_json::value::Value, bool> {
+) -> Result<serde_json::value::Value, SearchError> {
     let zoomeye_api_url = String::from("https://api.zoomeye.org/host/search");
 
     let mut params = HashMap::new();
@@ -19,71 +41,100 @@ async fn query_zoomeye(
     let param_num_str = &page_num.to_string()[..];
     params.insert("page", param_num_str);
 
-    let mut headers = HeaderMap::new();
-    headers.insert("API-KEY", token.parse().unwrap());
-
-    let client = if search_proxy.is_empty() {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
-            .build()
-            .unwrap()
-    } else {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(10))
-            .proxy(reqwest::Proxy::all(search_proxy.to_string()).unwrap())
-            .build()
-            .unwrap()
-    };
-
     let req = client
         .get(zoomeye_api_url)
         .query(&params)
-        .headers(headers)
-        .send()
-        .await;
-    match req {
-        Ok(r) => {
-            match r.json::<serde_json::Value>().await {
-                Ok(v) => return Ok(v),
-                Err(e) => {
-                    error!("  {:?}", e);
-                    return Err(false)
-                }
-            }
-        },
-        Err(e) => {
-            error!("  {:?}", e);
-            return Err(false)
-        }
+        .headers(api_key_header("API-KEY", token)?);
+    let data = send_json("zoomeye", req, rate_limited).await?;
+    if let Some(e) = error_of(&data) {
+        return Err(e);
+    }
+    if !data["matches"].is_array() {
+        return Err(SearchError::Malformed(String::from("no matches in the answer of zoomeye")));
+    }
+    Ok(data)
+}
+
+// Results left on the account this month
+async fn quota_zoomeye(client: &reqwest::Client, token: &str) -> Option<String> {
+    let req = client
+        .get("https://api.zoomeye.org/resources-info")
+        .headers(api_key_header("API-KEY", token).ok()?);
+    let data = send_json("zoomeye", req, rate_limited).await.ok()?;
+    match (&data["quota_info"]["remain_total_quota"], &data["resources"]["search"]) {
+        (serde_json::Value::Number(n), _) | (_, serde_json::Value::Number(n)) => Some(format!("{} results left", n)),
+        _ => None,
     }
 }
 
+/// Page through the results of `query`, `page_num` pages at most, without spending
+/// more than `budget` results on this run.
 pub async fn get_socks5_proxy_zoomeye(
     query: &str,
     page_num: i32,
     token: &str,
+    budget: Option<u64>,
     search_proxy: &str,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+) -> Result<Vec<Proxy>, SearchError> {
     info!("{}", "Searching from zoomeye...");
-    let mut result: Vec<String> = Vec::new();
+    let client = search_client(search_proxy, Duration::from_secs(10))?;
+    log_quota("zoomeye", "before", quota_zoomeye(&client, token).await);
+
+    let mut result: Vec<Proxy> = Vec::new();
+    let mut credits = Credits::new("zoomeye", budget);
     for page in 1..page_num + 1 {
-        match query_zoomeye(query, page, token, search_proxy).await {
-            Ok(data) => {
-                for i in data["matches"].as_array().unwrap().iter() {
-                    let mut ip_port = String::from("");
-                    let ip_str = i["ip"].to_string();
-                    let port_str = i["portinfo"]["port"].to_string();
-                    ip_port += &ip_str[1..ip_str.len() - 1];
-                    ip_port += ":";
-                    ip_port += &port_str;
-                    result.push(ip_port);
-                }
-            },
-            Err(_) => {
-                return Ok(result);
+        if !credits.allows(PAGE_SIZE as u64) {
+            break;
+        }
+        let data = match query_zoomeye(&client, query, page, token).await {
+            Ok(data) => data,
+            Err(e) if result.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("  {}, keeping the {} proxy found so far", e, result.len());
+                break;
             }
+        };
+        let matches = data["matches"].as_array().map(Vec::as_slice).unwrap_or_default();
+        credits.spend(matches.len() as u64);
+        for i in matches.iter() {
+            let (ip, port) = match (i["ip"].as_str(), i["portinfo"]["port"].as_u64()) {
+                (Some(ip), Some(port)) => (ip, port),
+                _ => continue,
+            };
+            match Proxy::parse(&join_host_port(ip, port), "zoomeye") {
+                Ok(proxy) => result.push(proxy),
+                Err(e) => debug!("  {}", e),
+            }
+        }
+        if matches.len() < PAGE_SIZE {
+            break;
         }
     }
-    info!(" - Get {} proxy from {}", result.len(), "zoomeye");
+
+    log_quota("zoomeye", "after", quota_zoomeye(&client, token).await);
+    info!(" - Get {} proxy from {}, {} credits spent", result.len(), "zoomeye", credits.spent());
     Ok(result)
 }
+
+/// zoomeye as a source of proxy, found with the protocol of the search filter.
+pub struct Zoomeye {
+    pub query: String,
+    pub page_num: i32,
+    pub token: String,
+    pub budget: Option<u64>,
+    pub protocol: Scheme,
+    pub search_proxy: String,
+}
+
+impl ProxySource for Zoomeye {
+    fn name(&self) -> &str {
+        "zoomeye"
+    }
+
+    fn fetch(&self) -> BoxFuture<'_, Result<Vec<Proxy>, SearchError>> {
+        Box::pin(async move {
+            let found = get_socks5_proxy_zoomeye(&self.query, self.page_num, &self.token, self.budget, &self.search_proxy).await?;
+            Ok(with_scheme(found, self.protocol))
+        })
+    }
+}
@@ -1,23 +1,35 @@
 #[forbid(unsafe_code)]
-
 pub mod socks5 {
     use log::{info, warn, error, debug};
-    use crate::utils::utils::parse_socks5_url;
+    use crate::proxypool::{ActiveConnection, ProxyPool};
+    use crate::proxypool::route::{RouteFilter, RouteParams};
+    use crate::proxypool::session::SessionTable;
+    use crate::utils::connect::{connect_hops, is_socks4_rejection, is_unreachable, socks5_bind, socks5_udp_associate, ChainError};
+    use crate::utils::proxy::Proxy;
 
     use fast_socks5::{
-        server::{Config, SimpleUserPassword, Socks5Server, Socks5Socket},
-        client::{self, Socks5Stream},
-        // socks4::client::Socks4Stream,
-        Result, SocksError,
+        server::{Authentication, Config, Socks5Server, Socks5Socket},
+        client::{Socks5Datagram, Socks5Listener},
+        util::target_addr::TargetAddr,
+        new_udp_header, parse_udp_request,
+        AuthenticationMethod, ReplyError, Result, Socks5Command, SocksError,
     };
     use anyhow::{anyhow, Context};
-    use tokio::io::{AsyncRead, AsyncWrite};
+    use clap::ValueEnum;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+    use tokio::net::{TcpStream, UdpSocket};
     use tokio::task;
+    use tokio::time::timeout;
     use tokio_stream::StreamExt;
-    use std::net::ToSocketAddrs;
+    use std::net::{IpAddr, SocketAddr};
+    use std::fmt;
+    use std::future::Future;
     use std::io::ErrorKind;
+    use std::time::Duration;
 
-    use rand::seq::SliceRandom;
+    // How recently a socks4 upstream has to have succeeded for its generic
+    // rejection to be blamed on the target rather than on the upstream
+    const RECENT_SUCCESS: Duration = Duration::from_secs(5 * 60);
 
     pub enum AuthMode {
         NoAuth,
@@ -27,36 +39,100 @@ pub mod socks5 {
         },
     }
 
-    pub async fn spawn_socks_server(request_timeout: u64, auth: AuthMode, listen_host: String, listen_port: u16, proxy_addr_pool: Vec<String>) -> Result<()> {
+    /// Accepts `<username>` followed by routing parameters, such as
+    /// `<username>-country-US-session-42`, see `RouteParams`. The routing
+    /// parameters have to stay within the `filter` of the listener.
+    pub struct RouteUserPassword {
+        pub username: String,
+        pub password: String,
+        pub filter: RouteFilter,
+    }
+
+    impl Authentication for RouteUserPassword {
+        fn authenticate(&self, username: &str, password: &str) -> bool {
+            let route = RouteParams::parse(username)
+                .and_then(|(base, route)| self.filter.narrow(&route.filter).map(|_| base));
+            match route {
+                Ok(base) => base == self.username && password == self.password,
+                Err(err) => {
+                    warn!("Invalid routing parameters in username `{}`: {}", username, err);
+                    false
+                }
+            }
+        }
+    }
+
+    /// Where the domain of a target is resolved, with `--dns`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum DnsMode {
+        /// The upstream proxy resolves the domain, except for socks4 proxies which cannot
+        Remote,
+        /// The domain is resolved on this host and the upstream proxy is given its IP
+        Local,
+    }
+
+    /// How a client request is relayed through the pool.
+    #[derive(Debug, Clone)]
+    pub struct RelayConfig {
+        /// How many upstream proxies to try for one request
+        pub attempts: usize,
+        /// Timeout of connecting to the target through one upstream proxy
+        pub connect_timeout: Duration,
+        pub dns: DnsMode,
+        /// How many members of the pool a connection goes through, the last one is the exit
+        pub chain: usize,
+        /// Fixed first hop in front of the members of the pool
+        pub chain_entry: Option<Proxy>,
+        /// Parent proxy every upstream proxy is reached through
+        pub via: Option<Proxy>,
+    }
+
+    pub async fn spawn_socks_server(request_timeout: u64, auth: AuthMode, listen_addr: String, filter: RouteFilter, proxy_pool: ProxyPool, relay: RelayConfig, sessions: SessionTable) -> Result<()> {
         let mut config = Config::default();
         config.set_request_timeout(request_timeout);
         config.set_dns_resolve(false);
+        // the command is executed through the upstream proxy in `handle_socket`
+        config.set_execute_command(false);
         config.set_transfer_data(false);
+        // UDP ASSOCIATE and BIND are relayed through upstream proxies supporting them,
+        // UDP cannot go through a `--via` parent proxy so it is refused right away
+        config.set_udp_support(relay.via.is_none());
+        config.set_bind_support(true);
 
         match auth {
             AuthMode::NoAuth => warn!("No authentication has been set!"),
             AuthMode::Password { username, password } => {
-                config.set_authentication(SimpleUserPassword { username, password });
+                config.set_authentication(RouteUserPassword { username, password, filter: filter.clone() });
                 info!("Simple auth system has been set.");
             }
         }
 
-        let listen_addr = format!("{}:{}", listen_host, listen_port.to_string());
-
         let mut listener = Socks5Server::bind(&listen_addr).await?;
         listener.set_config(config);
-    
+
         let mut incoming = listener.incoming();
-    
-        info!("Listen for socks5 connections @ {}, using proxypool[{}]", &listen_addr, proxy_addr_pool.len());
+
+        info!("Listen for socks5 connections @ {}, using proxypool[{}]", &listen_addr, proxy_pool.len());
 
         // Standard TCP loop
         while let Some(socket_res) = incoming.next().await {
             match socket_res {
                 Ok(socket) => {
-                    let proxy_addr = proxy_addr_pool.choose(&mut rand::thread_rng()).unwrap().to_string();
+                    let tcp = socket.get_socket_ref();
+                    let (peer_ip, local_ip) = match (tcp.peer_addr(), tcp.local_addr()) {
+                        (Ok(peer_addr), Ok(local_addr)) => (peer_addr.ip(), local_addr.ip()),
+                        (Err(err), _) | (_, Err(err)) => {
+                            error!("accept error = {:#}", err);
+                            continue;
+                        }
+                    };
+                    let proxy_pool = proxy_pool.clone();
+                    let sessions = sessions.clone();
+                    let filter = filter.clone();
+                    let relay = relay.clone();
+                    let listen_addr = listen_addr.clone();
                     task::spawn(async move {
-                        if let Err(err) = handle_socket(socket, proxy_addr).await {
+                        if let Err(err) = handle_socket(socket, (peer_ip, local_ip), &listen_addr, proxy_pool, relay, sessions, filter).await {
                             error!("socket handle error = {:#}", err);
                         }
                     });
@@ -69,7 +145,9 @@ pub mod socks5 {
         Ok(())
     }
 
-    async fn handle_socket<T>(socket: Socks5Socket<T>, proxy_addr: String) -> Result<()>
+    // `(peer_ip, local_ip)` are the addresses of the client and of this server
+    // on the client connection, accepted by the listener `listen_addr`
+    async fn handle_socket<T>(socket: Socks5Socket<T>, (peer_ip, local_ip): (IpAddr, IpAddr), listen_addr: &str, proxy_pool: ProxyPool, relay: RelayConfig, sessions: SessionTable, filter: RouteFilter) -> Result<()>
     where
         T: AsyncRead + AsyncWrite + Unpin,
     {
@@ -79,93 +157,570 @@ pub mod socks5 {
             .await
             .context("upgrade incoming socket to socks5")?;
 
-        // get resolved target addr
-        socks5_socket
-            .resolve_dns()
-            .await
-            .context("resolve target dns for incoming socket")?;
-        let socket_addr = socks5_socket
+        // routing parameters have been validated while authenticating
+        let (user, mut route) = match socks5_socket.auth() {
+            AuthenticationMethod::Password { username, .. } => {
+                RouteParams::parse(username).unwrap_or_else(|_| (username.clone(), RouteParams::default()))
+            }
+            AuthenticationMethod::None => (String::new(), RouteParams::default()),
+        };
+        route.filter = filter.narrow(&route.filter).unwrap_or(filter);
+
+        match socks5_socket.cmd() {
+            Some(Socks5Command::UDPAssociate) => route.filter.udp = true,
+            Some(Socks5Command::TCPBind) => route.filter.bind = true,
+            _ => {}
+        }
+        let session_key = sessions.key(listen_addr, &user, peer_ip, &route);
+
+        if route.filter.udp {
+            // `_active` counts this association against the upstream until it is closed
+            let associated = through_pool(&proxy_pool, &relay, &sessions, session_key, &route, &"udp association", |proxy| async move {
+                socks5_udp_associate(&proxy).await.map_err(|err| Failure::Upstream(SocksError::Other(err)))
+            }).await;
+            let (datagram, _active) = match associated {
+                Ok(associated) => associated,
+                Err(err) => {
+                    socks5_socket.reply_error(&err.reply()).await?;
+                    return Err(SocksError::Other(anyhow!("cannot associate udp: {}", err)));
+                }
+            };
+            return relay_udp(socks5_socket, peer_ip, local_ip, datagram, relay.dns).await;
+        }
+
+        if route.filter.bind {
+            // where the client expects the connection from, handed over as is
+            let expected = Target {
+                addr: socks5_socket.target_addr().context("find target address for incoming socket")?.clone(),
+                resolved: None,
+            };
+            // `_active` counts this connection against the upstream until it is closed
+            let bound = through_pool(&proxy_pool, &relay, &sessions, session_key, &route, &"bind", |proxy| {
+                let (host, port) = expected.host_port();
+                let via = relay.via.as_ref();
+                async move { socks5_bind(via, &proxy, host, port).await.map_err(|err| Failure::Upstream(SocksError::Other(err))) }
+            }).await;
+            let (bound, _active) = match bound {
+                Ok(bound) => bound,
+                Err(err) => {
+                    socks5_socket.reply_error(&err.reply()).await?;
+                    return Err(SocksError::Other(anyhow!("cannot bind: {}", err)));
+                }
+            };
+            return relay_bind(socks5_socket, bound).await;
+        }
+
+        // the domain is only resolved here with `--dns local`
+        let requested = socks5_socket
             .target_addr()
             .context("find target address for incoming socket")?
-            .to_socket_addrs()
-            .context("convert target address of incoming socket to socket addresses")?
-            .next()
-            .context("reach out to target of incoming socket")?;
-
-        // connect to downstream proxy
-
-        let proxy_addr_obj = parse_socks5_url(proxy_addr).unwrap();
-        let proxy_schema = proxy_addr_obj.0;
-        let proxy_host = proxy_addr_obj.3;
-        let proxy_port = proxy_addr_obj.4;
-
-
-
-        match proxy_schema.as_str() {
-            // socks4 unsupported...
-            // "socks4" => {
-            //     let mut stream = Socks4Stream::connect(
-            //             proxy_addr.clone().as_str()[9..proxy_addr.len()].to_string(),
-            //             socket_addr.ip().to_string(),
-            //             socket_addr.port(),
-            //             false,
-            //         )
-            //         .await
-            //         .context("connect to downstream proxy for incoming socket")?;
-            //     match tokio::io::copy_bidirectional(&mut stream, &mut socks5_socket).await {
-            //         Ok(res) => {
-            //             debug!("socket transfer closed ({}, {})", res.0, res.1);
-            //             Ok(())
-            //         }
-            //         Err(err) => match err.kind() {
-            //             ErrorKind::NotConnected => {
-            //                 debug!("socket transfer closed by client");
-            //                 Ok(())
-            //             },
-            //             ErrorKind::ConnectionReset => {
-            //                 debug!("socket transfer closed by downstream proxy");
-            //                 Ok(())
-            //             },
-            //             _ => Err(SocksError::Other(anyhow!(
-            //                 "socket transfer error: {:#}",
-            //                 err
-            //             )))
-            //         },
-            //     }
-            // },
-            "socks5" => {
-                let mut stream = Socks5Stream::connect(
-                        format!("{}:{}", proxy_host, proxy_port.to_string()),
-                        socket_addr.ip().to_string(),
-                        socket_addr.port(),
-                        client::Config::default(),
-                    )
-                    .await
-                    .context("connect to downstream proxy for incoming socket")?;
-                match tokio::io::copy_bidirectional(&mut stream, &mut socks5_socket).await {
-                    Ok(res) => {
-                        debug!("socket transfer closed ({}, {})", res.0, res.1);
-                        Ok(())
+            .clone();
+        let target = match Target::new(requested, relay.dns).await {
+            Ok(target) => target,
+            Err(err) => {
+                socks5_socket.reply_error(&ReplyError::HostUnreachable).await?;
+                return Err(SocksError::Other(err.context("resolve target dns for incoming socket")));
+            }
+        };
+
+        // `_active` counts this connection against the upstream until it is closed
+        let (mut stream, _active) = match connect_through_pool(&proxy_pool, &relay, &sessions, session_key, &route, &target).await {
+            Ok(connected) => connected,
+            Err(err) => {
+                socks5_socket.reply_error(&err.reply()).await?;
+                return Err(SocksError::Other(anyhow!("cannot reach {}: {}", target, err)));
+            }
+        };
+
+        socks5_socket.reply_success("127.0.0.1:0".parse().unwrap()).await?;
+
+        // copy data between our incoming client and the used downstream proxy
+        match tokio::io::copy_bidirectional(&mut stream, &mut socks5_socket).await {
+            Ok(res) => {
+                debug!("socket transfer closed ({}, {})", res.0, res.1);
+                Ok(())
+            }
+            Err(err) => match err.kind() {
+                ErrorKind::NotConnected => {
+                    debug!("socket transfer closed by client");
+                    Ok(())
+                },
+                ErrorKind::ConnectionReset => {
+                    debug!("socket transfer closed by downstream proxy");
+                    Ok(())
+                },
+                _ => Err(SocksError::Other(anyhow!(
+                    "socket transfer error: {:#}",
+                    err
+                )))
+            },
+        }
+    }
+
+    // Relay the datagrams of a UDP association through `datagram` for as long as
+    // the client keeps its control connection open
+    async fn relay_udp<T>(mut socks5_socket: Socks5Socket<T>, peer_ip: IpAddr, local_ip: IpAddr, datagram: Socks5Datagram<TcpStream>, dns: DnsMode) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        // the client sends its datagrams here, with the same header as to the upstream proxy
+        let client_socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+        socks5_socket.reply_success(client_socket.local_addr()?).await?;
+        debug!("udp association of {} relayed @ {}", peer_ip, client_socket.local_addr()?);
+
+        let mut client_addr: Option<SocketAddr> = None;
+        let mut client_buf = vec![0u8; 0x10000];
+        let mut upstream_buf = vec![0u8; 0x10000];
+        let mut control_buf = [0u8; 64];
+        loop {
+            tokio::select! {
+                received = client_socket.recv_from(&mut client_buf) => {
+                    let (size, from) = received?;
+                    // nobody else may use the association of the client
+                    if from.ip() != peer_ip {
+                        debug!("drop udp datagram from {}, not the client of the association", from);
+                        continue;
                     }
-                    Err(err) => match err.kind() {
-                        ErrorKind::NotConnected => {
-                            debug!("socket transfer closed by client");
-                            Ok(())
-                        },
-                        ErrorKind::ConnectionReset => {
-                            debug!("socket transfer closed by downstream proxy");
-                            Ok(())
+                    client_addr = Some(from);
+                    let (frag, target_addr, data) = match parse_udp_request(&client_buf[..size]).await {
+                        Ok(request) => request,
+                        Err(err) => {
+                            debug!("drop malformed udp datagram from {}: {:#}", from, err);
+                            continue;
+                        }
+                    };
+                    if frag != 0 {
+                        debug!("drop fragmented udp datagram from {}", from);
+                        continue;
+                    }
+                    let target_addr = match dns {
+                        DnsMode::Local => match resolve(&target_addr).await {
+                            Ok(resolved) => TargetAddr::Ip(resolved),
+                            Err(err) => {
+                                debug!("drop udp datagram to {}: {:#}", target_addr, err);
+                                continue;
+                            }
                         },
-                        _ => Err(SocksError::Other(anyhow!(
-                            "socket transfer error: {:#}",
-                            err
-                        )))
-                    },
+                        DnsMode::Remote => target_addr,
+                    };
+                    datagram.send_to(data, target_addr).await?;
+                }
+                received = datagram.recv_from(&mut upstream_buf) => {
+                    let (size, from) = received?;
+                    if let Some(client_addr) = client_addr {
+                        let mut packet = new_udp_header(from)?;
+                        packet.extend_from_slice(&upstream_buf[..size]);
+                        client_socket.send_to(&packet, client_addr).await?;
+                    }
+                }
+                // the association ends with the control connection
+                read = socks5_socket.read(&mut control_buf) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                },
+            }
+        }
+        debug!("udp association of {} closed", peer_ip);
+        Ok(())
+    }
+
+    // Hand the address the upstream proxy listens on over to the client, then
+    // relay the connection of the remote host once it came in
+    async fn relay_bind<T>(mut socks5_socket: Socks5Socket<T>, (listener, bind_addr): (Socks5Listener<TcpStream>, SocketAddr)) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        socks5_socket.reply_success(bind_addr).await?;
+        debug!("bind through upstream proxy @ {}", bind_addr);
+
+        let mut closed = [0u8; 1];
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    socks5_socket.reply_error(&reply_of(&err)).await?;
+                    return Err(err);
                 }
             },
-            _ => Err(SocksError::Other(anyhow!("unsupported protocol")))
+            // the client gave up before the remote host connected
+            _ = socks5_socket.read(&mut closed) => {
+                debug!("bind @ {} closed by client", bind_addr);
+                return Ok(());
+            }
+        };
+        let peer_addr = match peer_addr {
+            TargetAddr::Ip(addr) => addr,
+            TargetAddr::Domain(_, _) => "0.0.0.0:0".parse().unwrap(),
+        };
+        socks5_socket.reply_success(peer_addr).await?;
+        debug!("bind @ {} accepted {}", bind_addr, peer_addr);
+
+        let mut stream = stream.get_socket();
+        match tokio::io::copy_bidirectional(&mut stream, &mut socks5_socket).await {
+            Ok(res) => {
+                debug!("socket transfer closed ({}, {})", res.0, res.1);
+                Ok(())
+            }
+            Err(err) => match err.kind() {
+                ErrorKind::NotConnected | ErrorKind::ConnectionReset => {
+                    debug!("socket transfer closed: {}", err);
+                    Ok(())
+                },
+                _ => Err(SocksError::Other(anyhow!("socket transfer error: {:#}", err))),
+            },
+        }
+    }
+
+    /// What a client wants to reach: the address it asked for and, with
+    /// `--dns local`, what it has been resolved to.
+    pub struct Target {
+        pub addr: TargetAddr,
+        pub resolved: Option<SocketAddr>,
+    }
+
+    impl Target {
+        pub async fn new(addr: TargetAddr, dns: DnsMode) -> anyhow::Result<Self> {
+            let resolved = match dns {
+                DnsMode::Local => Some(resolve(&addr).await?),
+                DnsMode::Remote => None,
+            };
+            Ok(Self { addr, resolved })
+        }
+
+        // Host and port the upstream proxy is asked to connect to
+        pub fn host_port(&self) -> (String, u16) {
+            match (&self.resolved, &self.addr) {
+                (Some(resolved), _) => (resolved.ip().to_string(), resolved.port()),
+                (None, TargetAddr::Ip(addr)) => (addr.ip().to_string(), addr.port()),
+                (None, TargetAddr::Domain(domain, port)) => (domain.clone(), *port),
+            }
+        }
+    }
+
+    impl fmt::Display for Target {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.addr)
+        }
+    }
+
+    /// Why no upstream proxy could reach a target.
+    #[derive(Debug)]
+    pub enum UpstreamError {
+        /// No member of the pool matches the routing filter
+        NoMatch(RouteFilter),
+        /// Every attempt failed, `last` is the error of the last one
+        Failed { attempts: usize, last: SocksError },
+        /// An upstream proxy answered that the target itself cannot be reached
+        Unreachable(SocksError),
+    }
+
+    impl UpstreamError {
+        /// What to reply to a socks5 client
+        pub fn reply(&self) -> ReplyError {
+            match self {
+                // the target refused what a socks4 upstream only rejected
+                UpstreamError::Unreachable(err) if is_socks4_rejection(err) => ReplyError::ConnectionRefused,
+                UpstreamError::Failed { last, .. } | UpstreamError::Unreachable(last) => reply_of(last),
+                UpstreamError::NoMatch(_) => ReplyError::GeneralFailure,
+            }
+        }
+    }
+
+    // Why one attempt through a member of the pool failed
+    enum Failure {
+        /// The upstream proxy is down or broken, another member is tried
+        Upstream(SocksError),
+        /// Another hop of the chain is down or broken, it is blamed instead of the upstream proxy
+        Hop(String, SocksError),
+        /// Not enough members are left for a chain
+        NoMatch,
+        /// The upstream proxy works but cannot reach the target, no other member would
+        Target(SocksError),
+    }
+
+    impl From<SocksError> for Failure {
+        fn from(err: SocksError) -> Self {
+            match is_unreachable(&err) {
+                true => Failure::Target(err),
+                false => Failure::Upstream(err),
+            }
+        }
+    }
+
+    // What to reply to a socks5 client when an upstream proxy failed with `err`
+    fn reply_of(err: &SocksError) -> ReplyError {
+        match err {
+            SocksError::ReplyError(reply) => *reply,
+            _ => ReplyError::GeneralFailure,
+        }
+    }
+
+    impl fmt::Display for UpstreamError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                UpstreamError::NoMatch(filter) => {
+                    write!(f, "no upstream proxy in proxypool matches {:?}", filter)
+                }
+                UpstreamError::Failed { attempts, last } => {
+                    write!(f, "no upstream proxy could connect after {} attempt(s), last error: {:#}", attempts, last)
+                }
+                UpstreamError::Unreachable(err) => write!(f, "{:#}", err),
+            }
+        }
+    }
+
+    /// Connect to `target` through the pool. A sticky session goes to its
+    /// pinned proxy first, as long as it is still in the pool, then it fails
+    /// over to other members up to `relay.attempts` times.
+    ///
+    /// With `--chain`, the proxy picked that way is the exit of the chain and
+    /// the hops before it are members matching the same filter, picked at
+    /// random. A failed chain counts against the hop which failed.
+    ///
+    /// The generic rejection of a socks4 exit only stands for the target when
+    /// the exit succeeded recently, otherwise it counts against the exit.
+    pub async fn connect_through_pool(
+        proxy_pool: &ProxyPool,
+        relay: &RelayConfig,
+        sessions: &SessionTable,
+        session_key: Option<String>,
+        route: &RouteParams,
+        target: &Target,
+    ) -> std::result::Result<(TcpStream, ActiveConnection), UpstreamError> {
+        // a chain needs its exit and every hop before it out of the pool
+        let fixed: Vec<String> = relay.via.iter().chain(relay.chain_entry.iter()).map(|p| p.key()).collect();
+        if relay.chain > 1 && proxy_pool.count_matching(&route.filter, &fixed) < relay.chain {
+            return Err(UpstreamError::NoMatch(route.filter.clone()));
+        }
+        through_pool(proxy_pool, relay, sessions, session_key, route, target, |exit| async move {
+            let exit_key = exit.key();
+            let exit_trusted = exit.health.succeeded_within(RECENT_SUCCESS);
+            let hops = chain_to(proxy_pool, relay, &route.filter, exit).ok_or(Failure::NoMatch)?;
+            let (host, port) = target.host_port();
+            connect_hops(&hops, host, port).await.map_err(|err| match err {
+                ChainError::Target(err) => Failure::Target(err),
+                ChainError::Hop(index, err) if index == hops.len() - 1 && exit_trusted && is_socks4_rejection(&err) => Failure::Target(err),
+                ChainError::Hop(index, err) if hops[index].key() == exit_key => Failure::Upstream(err),
+                ChainError::Hop(index, err) => Failure::Hop(hops[index].key(), err),
+            })
+        }).await
+    }
+
+    // Every hop of the chain ending with `exit`, starting with the `--via` parent
+    // proxy, `None` if there are not enough members left for it
+    fn chain_to(proxy_pool: &ProxyPool, relay: &RelayConfig, filter: &RouteFilter, exit: Proxy) -> Option<Vec<Proxy>> {
+        let mut hops: Vec<Proxy> = relay.via.iter().chain(relay.chain_entry.iter()).cloned().collect();
+        if relay.chain > 1 {
+            let excluding: Vec<String> = hops.iter().chain([&exit]).map(|p| p.key()).collect();
+            let middle = proxy_pool.pick_random(relay.chain - 1, filter, &excluding);
+            if middle.len() < relay.chain - 1 {
+                debug!("not enough proxy in proxypool matching {:?} for a chain of {}", filter, relay.chain);
+                return None;
+            }
+            hops.extend(middle);
+        }
+        hops.push(exit);
+        if hops.len() > 1 {
+            let path: Vec<String> = hops.iter().map(|p| p.to_string()).collect();
+            debug!("chain {}", path.join(" -> "));
+        }
+        Some(hops)
+    }
+
+    // Run `connect` with members of the pool until one succeeds, `target` only
+    // names what is being reached in the logs. A target which cannot be reached
+    // is answered right away, without counting against the member.
+    async fn through_pool<S, F, Fut>(
+        proxy_pool: &ProxyPool,
+        relay: &RelayConfig,
+        sessions: &SessionTable,
+        session_key: Option<String>,
+        route: &RouteParams,
+        target: &(dyn fmt::Display + Sync),
+        connect: F,
+    ) -> std::result::Result<(S, ActiveConnection), UpstreamError>
+    where
+        F: Fn(Proxy) -> Fut,
+        Fut: Future<Output = std::result::Result<S, Failure>>,
+    {
+        let mut pinned = session_key
+            .as_deref()
+            .and_then(|key| sessions.get(key))
+            .and_then(|proxy_key| proxy_pool.acquire(&proxy_key, &route.filter));
+
+        let mut tried: Vec<String> = Vec::new();
+        let mut last_err: Option<SocksError> = None;
+        loop {
+            if tried.len() >= relay.attempts {
+                break;
+            }
+            let selected = pinned
+                .take()
+                .or_else(|| proxy_pool.select_excluding(&tried, &route.filter, route.strategy));
+            let (proxy, active) = match selected {
+                Some(selected) => selected,
+                None => break,
+            };
+            let proxy_addr = proxy.key();
+            tried.push(proxy_addr.clone());
+
+            // the member itself unless another hop of its chain failed
+            let mut blamed = proxy_addr.clone();
+            match timeout(relay.connect_timeout, connect(proxy)).await {
+                Ok(Ok(stream)) => {
+                    proxy_pool.report_success(&proxy_addr);
+                    if let Some(key) = session_key {
+                        debug!("session `{}` pinned to {}", key, proxy_addr);
+                        sessions.pin(key, proxy_addr);
+                    }
+                    return Ok((stream, active));
+                }
+                Ok(Err(Failure::Target(err))) => {
+                    debug!("{} cannot reach {}: {:#}", proxy_addr, target, err);
+                    return Err(UpstreamError::Unreachable(err));
+                }
+                Ok(Err(Failure::NoMatch)) => break,
+                Ok(Err(Failure::Upstream(err))) => {
+                    debug!("connect to {} through {} failed: {:#}", target, proxy_addr, err);
+                    last_err = Some(err);
+                }
+                Ok(Err(Failure::Hop(hop, err))) => {
+                    debug!("connect to {} through {} failed at {}: {:#}", target, proxy_addr, hop, err);
+                    last_err = Some(err);
+                    blamed = hop;
+                }
+                Err(_) => {
+                    debug!("connect to {} through {} timed out", target, proxy_addr);
+                    last_err = Some(ReplyError::TtlExpired.into());
+                }
+            }
+            if proxy_pool.report_failure(&blamed) {
+                warn!("{} failed too many times, evicted from proxypool[{}]", blamed, proxy_pool.len());
+            }
+        }
+        Err(match last_err {
+            Some(last) => UpstreamError::Failed { attempts: tried.len(), last },
+            None => UpstreamError::NoMatch(route.filter.clone()),
+        })
+    }
+
+    // Resolve `addr` on this host
+    async fn resolve(addr: &TargetAddr) -> anyhow::Result<SocketAddr> {
+        match addr.clone().resolve_dns().await? {
+            TargetAddr::Ip(resolved) => Ok(resolved),
+            TargetAddr::Domain(domain, _) => Err(anyhow!("cannot resolve {}", domain)),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::proxypool::session::StickyKind;
+        use crate::utils::proxy::Scheme;
+        use std::time::SystemTime;
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        // An upstream proxy which rejects every connect request with `code`
+        async fn rejecting_upstream(scheme: Scheme, code: u8) -> Proxy {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let _ = match scheme {
+                        Scheme::Socks5 => socks5_reject(&mut stream, code).await,
+                        _ => socks4_reject(&mut stream, code).await,
+                    };
+                }
+            });
+            Proxy::new(scheme, String::from("127.0.0.1"), port, "test")
+        }
+
+        async fn socks5_reject(stream: &mut TcpStream, code: u8) -> std::io::Result<()> {
+            let mut greeting = [0u8; 2];
+            stream.read_exact(&mut greeting).await?;
+            let mut methods = vec![0u8; greeting[1] as usize];
+            stream.read_exact(&mut methods).await?;
+            stream.write_all(&[5, 0]).await?;
+            // request to an IPv4 target
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await?;
+            stream.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]).await
+        }
+
+        async fn socks4_reject(stream: &mut TcpStream, code: u8) -> std::io::Result<()> {
+            // request to an IPv4 target, with an empty user id
+            let mut request = [0u8; 9];
+            stream.read_exact(&mut request).await?;
+            stream.write_all(&[0, code, 0, 0, 0, 0, 0, 0]).await
+        }
+
+        fn relay(attempts: usize) -> RelayConfig {
+            RelayConfig {
+                attempts,
+                connect_timeout: Duration::from_secs(5),
+                dns: DnsMode::Remote,
+                chain: 1,
+                chain_entry: None,
+                via: None,
+            }
+        }
+
+        // Connect to a local target through `pool`, which is expected to fail
+        async fn fail_through(pool: &ProxyPool, attempts: usize) -> UpstreamError {
+            let sessions = SessionTable::new(StickyKind::None, Duration::from_secs(60));
+            let target = Target { addr: TargetAddr::Ip("127.0.0.1:9".parse().unwrap()), resolved: None };
+            connect_through_pool(pool, &relay(attempts), &sessions, None, &RouteParams::default(), &target)
+                .await
+                .err()
+                .expect("every upstream proxy rejects the request")
+        }
+
+        fn failures(pool: &ProxyPool) -> Vec<u64> {
+            pool.members().iter().map(|p| p.health.failures).collect()
+        }
+
+        #[tokio::test]
+        async fn target_refusal_neither_fails_over_nor_demotes() {
+            let pool = ProxyPool::new(vec![
+                rejecting_upstream(Scheme::Socks5, 5).await,
+                rejecting_upstream(Scheme::Socks5, 5).await,
+            ]);
+            let err = fail_through(&pool, 2).await;
+            assert!(matches!(err, UpstreamError::Unreachable(_)), "{}", err);
+            assert!(matches!(err.reply(), ReplyError::ConnectionRefused));
+            assert_eq!(failures(&pool), [0, 0]);
+        }
+
+        #[tokio::test]
+        async fn upstream_failure_fails_over_and_demotes() {
+            let pool = ProxyPool::new(vec![
+                rejecting_upstream(Scheme::Socks5, 1).await,
+                rejecting_upstream(Scheme::Socks5, 1).await,
+            ]);
+            let err = fail_through(&pool, 2).await;
+            assert!(matches!(err, UpstreamError::Failed { attempts: 2, .. }), "{}", err);
+            assert!(matches!(err.reply(), ReplyError::GeneralFailure));
+            assert_eq!(failures(&pool), [1, 1]);
+        }
+
+        #[tokio::test]
+        async fn socks4_rejection_demotes_an_untrusted_upstream() {
+            let pool = ProxyPool::new(vec![rejecting_upstream(Scheme::Socks4, 91).await]);
+            let err = fail_through(&pool, 1).await;
+            assert!(matches!(err, UpstreamError::Failed { attempts: 1, .. }), "{}", err);
+            assert_eq!(failures(&pool), [1]);
+        }
+
+        #[tokio::test]
+        async fn socks4_rejection_of_a_recently_working_upstream_is_the_target() {
+            let mut proxy = rejecting_upstream(Scheme::Socks4, 91).await;
+            proxy.health.last_success = Some(SystemTime::now());
+            let pool = ProxyPool::new(vec![proxy]);
+            let err = fail_through(&pool, 1).await;
+            assert!(matches!(err, UpstreamError::Unreachable(_)), "{}", err);
+            assert!(matches!(err.reply(), ReplyError::ConnectionRefused));
+            assert_eq!(failures(&pool), [0]);
         }
-        // copy data between our incoming client and the used downstream proxy
-        
     }
-}
\ No newline at end of file
+}
@@ -1,23 +1,35 @@
 use futures::{stream, StreamExt};
 use log::{debug, info};
 use serde_json;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use url::Url;
+
+use crate::utils::connect::{connect_chain, socks5_bind, socks5_udp_associate};
+use crate::utils::proxy::{Anonymity, Proxy, Scheme};
 
 // Set Max count of request
 const PARALLEL_REQUESTS: usize = 32;
 
-// - get proxy physical location
-// - test proxy delay
-async fn check_availability(
-    proxy_address: String,
-    delay_test_address: String,
+// DNS query of the A record of ipinfo.io, sent to find out whether a proxy relays UDP
+const DNS_QUERY: [u8; 27] = [
+    0x52, 0x48, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    6, b'i', b'p', b'i', b'n', b'f', b'o', 2, b'i', b'o', 0,
+    0x00, 0x01, 0x00, 0x01,
+];
+
+// Location of the exit ip and delay, with reqwest speaking the proxy protocol
+async fn probe_reqwest(
+    proxy_address: &Proxy,
+    delay_test_address: &str,
     time_out: Duration,
-) -> Result<(String, String, String, String), Box<dyn std::error::Error>> {
+) -> Result<(serde_json::Value, Duration), Box<dyn std::error::Error>> {
     let test_url = String::from("http://ipinfo.io");
-    debug!("Now checking {}", proxy_address);
-
-    let proxy = reqwest::Proxy::all(proxy_address.clone())?;
+    let proxy = reqwest::Proxy::all(proxy_address.url())?;
 
     let client1 = reqwest::Client::builder()
         .proxy(proxy.clone())
@@ -39,105 +51,274 @@ async fn check_availability(
         .await;
 
     // delay test
-    let _ = client2.get(&delay_test_address).send().await?;
+    let start = Instant::now();
+    let _ = client2.get(delay_test_address).send().await?;
+    let delay = start.elapsed();
 
     match res {
-        Ok(res) => {
-            let ip = match res["ip"].as_str() {
-                Some(ip) => ip.trim_matches('"'),
-                None => {
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Can not find ip address from response",
-                    )))
-                }
-            };
-            let city = match res["city"].as_str() {
-                Some(city) => city.trim_matches('"'),
-                None => {
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Can not find city from response",
-                    )))
-                }
-            };
-            let country = match res["country"].as_str() {
-                Some(country) => country.trim_matches('"'),
-                None => {
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Can not find country from response",
-                    )))
-                }
-            };
-            Ok((proxy_address.clone(), ip.to_string(), city.to_string(), country.to_string()))
-        },
+        Ok(res) => Ok((res, delay)),
         Err(err) => {
             debug!("{:?}", err);
-            return Err(Box::new(err))
+            Err(Box::new(err))
         }
     }
 }
 
-// Test the availability of the socks5 proxy
-pub async fn test_connect(
-    proxy_list: Vec<String>,
+// Send a GET request through a tunnel opened by hand across every proxy of `hops`,
+// reqwest does not speak socks4, can not chain proxy and would not use CONNECT for
+// http urls. There is no tls here, so for https only the tunnel is opened and the
+// body is empty.
+async fn tunnel_get(hops: &[Proxy], url: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let url = Url::parse(url)?;
+    let host = url.host_str().ok_or("host not found in url")?;
+    let port = url.port_or_known_default().ok_or("port not found in url")?;
+    let mut stream = connect_chain(hops, host.to_string(), port).await?;
+    if url.scheme() != "http" {
+        return Ok(String::new());
+    }
+
+    // HTTP/1.0 so that the body is never chunked
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\nUser-Agent: curl/8.0.0\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or("invalid http response")?;
+    match head.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(body.to_string()),
+        _ => Err(format!("unexpected http response `{}`", head.lines().next().unwrap_or_default()).into()),
+    }
+}
+
+// Same as `probe_reqwest`, through a socks4, socks4a or http CONNECT tunnel, or any
+// proxy behind the `--via` parent proxy
+async fn probe_tunnel(
+    hops: &[Proxy],
+    delay_test_address: &str,
+    time_out: Duration,
+) -> Result<(serde_json::Value, Duration), Box<dyn std::error::Error>> {
+    let body = timeout(time_out, tunnel_get(hops, "http://ipinfo.io/json")).await??;
+    let res = serde_json::from_str::<serde_json::Value>(&body)?;
+
+    // delay test
+    let start = Instant::now();
+    timeout(time_out, tunnel_get(hops, delay_test_address)).await??;
+    let delay = start.elapsed();
+
+    Ok((res, delay))
+}
+
+// Whether a socks5 proxy relays UDP, by asking the DNS server `udp_test_address` through it
+async fn probe_udp(
+    proxy_address: &Proxy,
+    udp_test_address: SocketAddr,
+    time_out: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let datagram = timeout(time_out, socks5_udp_associate(proxy_address)).await??;
+    datagram.send_to(&DNS_QUERY, udp_test_address).await?;
+    let mut buf = vec![0u8; 0x10000];
+    timeout(time_out, datagram.recv_from(&mut buf)).await??;
+    Ok(())
+}
+
+// Whether a socks5 proxy accepts BIND, by connecting from here to where it listens
+async fn probe_bind(
+    via: Option<&Proxy>,
+    proxy_address: &Proxy,
+    time_out: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bind = socks5_bind(via, proxy_address, "0.0.0.0".to_string(), 0);
+    let (listener, bind_addr) = timeout(time_out, bind).await??;
+    let _remote = match via {
+        Some(via) => {
+            let hops = std::slice::from_ref(via);
+            timeout(time_out, connect_chain(hops, bind_addr.ip().to_string(), bind_addr.port())).await??
+        }
+        None => timeout(time_out, TcpStream::connect(bind_addr)).await??,
+    };
+    timeout(time_out, listener.accept()).await??;
+    Ok(())
+}
+
+// - get proxy physical location
+// - test proxy delay
+// - judge the anonymity from the exit ip
+// - find out whether a socks5 proxy relays UDP and accepts BIND, only the first
+//   time it is checked so that re-checks stay short and keep what was found then
+async fn check_availability(
+    mut proxy_address: Proxy,
+    via: Option<Proxy>,
+    own_ip: Option<String>,
     delay_test_address: String,
+    udp_test_address: SocketAddr,
     time_out: Duration,
-    zone: i8,
-) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+) -> Result<Proxy, Box<dyn std::error::Error>> {
+    debug!("Now checking {}", proxy_address);
+
+    let (res, delay) = match (&via, proxy_address.scheme) {
+        (None, Scheme::Socks5) => probe_reqwest(&proxy_address, &delay_test_address, time_out).await?,
+        _ => {
+            let hops: Vec<Proxy> = via.iter().chain([&proxy_address]).cloned().collect();
+            probe_tunnel(&hops, &delay_test_address, time_out).await?
+        }
+    };
+
+    let ip = match res["ip"].as_str() {
+        Some(ip) => ip.trim_matches('"'),
+        None => {
+            return Err(Box::new(std::io::Error::other(
+                "Can not find ip address from response",
+            )))
+        }
+    };
+    let city = match res["city"].as_str() {
+        Some(city) => city.trim_matches('"'),
+        None => {
+            return Err(Box::new(std::io::Error::other(
+                "Can not find city from response",
+            )))
+        }
+    };
+    let country = match res["country"].as_str() {
+        Some(country) => country.trim_matches('"'),
+        None => {
+            return Err(Box::new(std::io::Error::other(
+                "Can not find country from response",
+            )))
+        }
+    };
+    proxy_address.anonymity = match own_ip {
+        Some(own_ip) if own_ip == ip => Anonymity::Transparent,
+        Some(_) => Anonymity::Anonymous,
+        None => Anonymity::Unknown,
+    };
+    proxy_address.ip = ip.to_string();
+    proxy_address.city = city.to_string();
+    proxy_address.country = country.to_string();
+    proxy_address.latency = delay;
+    if proxy_address.scheme == Scheme::Socks5 && proxy_address.health.last_check.is_none() {
+        // UDP can not go through the parent proxy
+        match via {
+            Some(_) => proxy_address.udp = false,
+            None => match probe_udp(&proxy_address, udp_test_address, time_out).await {
+                Ok(()) => proxy_address.udp = true,
+                Err(e) => {
+                    debug!("{} does not relay udp: {}", proxy_address, e);
+                    proxy_address.udp = false;
+                }
+            },
+        }
+        match probe_bind(via.as_ref(), &proxy_address, time_out).await {
+            Ok(()) => proxy_address.bind = true,
+            Err(e) => {
+                debug!("{} does not accept bind: {}", proxy_address, e);
+                proxy_address.bind = false;
+            }
+        }
+    }
+    proxy_address.health.last_check = Some(SystemTime::now());
+    Ok(proxy_address)
+}
+
+/// Settings shared by every availability check, so the startup check and the
+/// background tasks validate proxies the same way.
+#[derive(Debug, Clone)]
+pub struct CheckConfig {
+    pub delay_test_address: String,
+    pub udp_test_address: SocketAddr,
+    pub time_out: Duration,
+    pub zone: i8,
+    /// Parent proxy every proxy is reached through
+    pub via: Option<Proxy>,
+}
+
+// Whether a proxy exiting in `country` matches the `-z --zone` setting
+pub fn in_zone(zone: i8, country: &str) -> bool {
+    let country = country.to_uppercase();
+    match zone {
+        // Inland China NOT include HK TW MO
+        0i8 => country == "CN",
+        // Only include HK MO TW
+        1i8 => ["HK", "MO", "TW"].contains(&country.as_str()),
+        // All zone of China both of 0 and 1
+        2i8 => ["CN", "HK", "MO", "TW"].contains(&country.as_str()),
+        // Include CN HK MO TW
+        3i8 => !["CN", "HK", "MO", "TW"].contains(&country.as_str()),
+        // All of the world [defult]
+        _ => true,
+    }
+}
+
+// Our own public ip, to find out which proxy give it away. Behind a parent proxy,
+// that is the exit ip of the parent proxy.
+async fn get_own_ip(via: Option<&Proxy>, time_out: Duration) -> Option<String> {
+    if let Some(via) = via {
+        let body = timeout(time_out, tunnel_get(std::slice::from_ref(via), "http://ipinfo.io/json"))
+            .await
+            .ok()?
+            .ok()?;
+        let res = serde_json::from_str::<serde_json::Value>(&body).ok()?;
+        return res["ip"].as_str().map(|ip| ip.to_string());
+    }
+    let client = reqwest::Client::builder().timeout(time_out).build().ok()?;
+    let res = client
+        .get("http://ipinfo.io")
+        .send()
+        .await
+        .ok()?
+        .json::<serde_json::Value>()
+        .await
+        .ok()?;
+    res["ip"].as_str().map(|ip| ip.to_string())
+}
+
+// Test the availability of the socks5 proxy
+pub async fn test_connect(
+    proxy_list: Vec<Proxy>,
+    check_config: &CheckConfig,
+) -> Result<Vec<Proxy>, Box<dyn std::error::Error>> {
     info!("{}", "Test connect...");
-    let pl = proxy_list.clone();
-    let z = zone.clone();
-    let d = delay_test_address;
-    let t = time_out;
-    let result: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let z = check_config.zone;
+    let own_ip = get_own_ip(check_config.via.as_ref(), check_config.time_out).await;
+    if own_ip.is_none() {
+        debug!("Can not find our own ip, the anonymity of proxy is unknown");
+    }
+    let result: Arc<Mutex<Vec<Proxy>>> = Arc::new(Mutex::new(Vec::new()));
 
-    let responses = stream::iter(pl)
+    let responses = stream::iter(proxy_list)
         .map(|i| {
             let result = Arc::clone(&result);
-            // let d = Arc::clone(&d);
-            // let t = Arc::clone(&t);
-            let d = d.clone();
-            let t = t.clone();
+            let d = check_config.delay_test_address.clone();
+            let u = check_config.udp_test_address;
+            let t = check_config.time_out;
+            let via = check_config.via.clone();
+            let own_ip = own_ip.clone();
             async move {
-                let resp = check_availability(i, d, t).await;
+                let resp = check_availability(i, via, own_ip, d, u, t).await;
                 let mut guard = result.lock().unwrap();
                 match resp {
                     Ok(i) => {
-                        // `i.0` is `proxy_address` as socks5://206.189.157.253:59166
-                        // `i.1` is `ip` as 206.189.157.253, maybe not as the same as i.0
-                        // `i.2` is `city` as Singapore
-                        // `i.3` is `country` as SG
-                        match z {
-                            // Inland China NOT include HK TW MO
-                            0i8 => {
-                                if i.3.to_uppercase().as_str() != "CN" {return }
-                            },
-                            // Only include HK MO TW
-                            1i8 => {
-                                if !vec!["HK", "MO", "TW"].contains(&i.3.to_uppercase().as_str()) {return }
-                            },
-                            // All zone of China both of 0 and 1
-                            2i8 => {
-                                if !vec!["CN", "HK", "MO", "TW"].contains(&i.3.to_uppercase().as_str()) {return }
-                            },
-                            // Include CN HK MO TW
-                            3i8 => {
-                                if vec!["CN", "HK", "MO", "TW"].contains(&i.3.to_uppercase().as_str()) {return }
-                            },
-                            // All of the world [defult]
-                            4i8 => { },
-                            _ => { },
+                        // `i.ip` is the exit ip as 206.189.157.253, maybe not as the same as `i.host`
+                        if !in_zone(z, &i.country) {
+                            return;
                         }
                         info!(
-                            "Find available proxy: {}, out to: {} @ {}[{}]",
-                            i.0.clone(),
-                            i.1,
-                            i.2,
-                            i.3,
+                            "Find available proxy: {}, out to: {} @ {}[{}], delay {}ms",
+                            i,
+                            i.ip,
+                            i.city,
+                            i.country,
+                            i.latency.as_millis(),
                         );
-                        guard.push(i.0);
+                        guard.push(i);
                     }
                     Err(_e) => {
                         debug!("{:?}", _e);
@@ -149,7 +330,6 @@ pub async fn test_connect(
 
     responses.for_each(|_| async {}).await;
     let guard = result.lock().unwrap();
-    // let guard = result.into_inner().unwrap();
     Ok(guard.clone())
 }
 
@@ -0,0 +1,221 @@
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose, Engine as _};
+use fast_socks5::client::{self, Socks5Datagram, Socks5Listener, Socks5Stream};
+use fast_socks5::socks4::{self, client::Socks4Stream, Socks4Command};
+use fast_socks5::util::target_addr::{TargetAddr, ToTargetAddr};
+use fast_socks5::{AuthenticationMethod, ReplyError, Socks5Command, SocksError};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+
+use crate::utils::proxy::{join_host_port, Proxy, Scheme};
+
+// Longest response head accepted from a proxy
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// Where a chain of proxy broke.
+#[derive(Debug)]
+pub enum ChainError {
+    /// The hop at this index of the chain is down or broken
+    Hop(usize, SocksError),
+    /// Every hop works but the last one could not reach the target
+    Target(SocksError),
+}
+
+impl From<ChainError> for SocksError {
+    fn from(err: ChainError) -> Self {
+        match err {
+            ChainError::Hop(_, err) | ChainError::Target(err) => err,
+        }
+    }
+}
+
+/// Open a tunnel to `host:port` through every proxy of `hops` in turn, each one
+/// being asked to connect to the next one. The socks5 reply of a failed hop is
+/// kept in the error.
+pub async fn connect_chain(hops: &[Proxy], host: String, port: u16) -> fast_socks5::Result<TcpStream> {
+    Ok(connect_hops(hops, host, port).await?)
+}
+
+/// Same as `connect_chain`, telling which hop failed. A hop which could not
+/// reach the next one puts the blame on the next one.
+pub async fn connect_hops(hops: &[Proxy], host: String, port: u16) -> Result<TcpStream, ChainError> {
+    let first = hops
+        .first()
+        .context("empty chain of proxy")
+        .map_err(|err| ChainError::Hop(0, err.into()))?;
+    let mut stream = TcpStream::connect(first.addr())
+        .await
+        .context("connect to downstream proxy")
+        .map_err(|err| ChainError::Hop(0, err.into()))?;
+    for (index, (hop, next)) in hops.iter().zip(hops.iter().skip(1)).enumerate() {
+        stream = handshake(stream, hop, next.host.clone(), next.port)
+            .await
+            .map_err(|err| match is_unreachable(&err) {
+                true => ChainError::Hop(index + 1, err),
+                false => ChainError::Hop(index, err),
+            })?;
+    }
+    let last = hops.len() - 1;
+    handshake(stream, &hops[last], host, port)
+        .await
+        .map_err(|err| match is_unreachable(&err) {
+            true => ChainError::Target(err),
+            false => ChainError::Hop(last, err),
+        })
+}
+
+/// Connect to `proxy` itself, through the parent proxy `via` if any.
+pub async fn dial(via: Option<&Proxy>, proxy: &Proxy) -> fast_socks5::Result<TcpStream> {
+    match via {
+        Some(via) => connect_chain(std::slice::from_ref(via), proxy.host.clone(), proxy.port).await,
+        None => Ok(TcpStream::connect(proxy.addr())
+            .await
+            .context("connect to downstream proxy")?),
+    }
+}
+
+/// Whether `err` is the reply of a proxy which works but could not reach the
+/// host it was asked to, such as a closed port, rather than a failure of the proxy.
+pub fn is_unreachable(err: &SocksError) -> bool {
+    matches!(
+        err,
+        SocksError::ReplyError(ReplyError::ConnectionRefused | ReplyError::HostUnreachable | ReplyError::NetworkUnreachable)
+    )
+}
+
+/// Whether `err` is the only generic rejection of socks4, code 91. A closed port
+/// and a broken proxy rejecting every request both answer it, so it is up to
+/// the caller to tell them apart.
+pub fn is_socks4_rejection(err: &SocksError) -> bool {
+    matches!(err, SocksError::ReplySocks4Error(socks4::ReplyError::GeneralFailure))
+}
+
+// Ask `proxy`, which `stream` already reaches, to connect to `host:port`
+async fn handshake(stream: TcpStream, proxy: &Proxy, host: String, port: u16) -> fast_socks5::Result<TcpStream> {
+    let target_addr = (host.as_str(), port).to_target_addr()?;
+    match proxy.scheme {
+        Scheme::Socks5 => {
+            let mut stream = Socks5Stream::use_stream(stream, socks5_auth(proxy), client::Config::default()).await?;
+            stream.request(Socks5Command::TCPConnect, target_addr).await?;
+            Ok(stream.get_socket())
+        }
+        Scheme::Socks4 | Scheme::Socks4a => {
+            // socks4a hands the domain over to the proxy, socks4 only knows IPv4
+            // so the domain is resolved here whatever `--dns` says
+            let mut stream = Socks4Stream::use_stream(stream)?;
+            stream
+                .request(Socks4Command::Connect, target_addr, proxy.scheme == Scheme::Socks4)
+                .await
+                .map_err(|err| match err {
+                    // code 91 is kept as it is, see `is_socks4_rejection`
+                    SocksError::ReplySocks4Error(socks4::ReplyError::HostUnreachable) => ReplyError::HostUnreachable.into(),
+                    SocksError::ReplySocks4Error(socks4::ReplyError::AddressTypeNotSupported) => ReplyError::AddressTypeNotSupported.into(),
+                    err => err,
+                })?;
+            Ok(stream.get_socket())
+        }
+        Scheme::Http => Ok(http_tunnel(stream, proxy, &host, port)
+            .await
+            .context("connect to downstream proxy")?),
+    }
+}
+
+fn socks5_auth(proxy: &Proxy) -> Option<AuthenticationMethod> {
+    proxy
+        .credentials
+        .clone()
+        .map(|(username, password)| AuthenticationMethod::Password { username, password })
+}
+
+/// Open a tunnel to `host:port` with CONNECT, over a `stream` which already
+/// reaches the HTTP proxy `proxy`. The returned stream carries the data of the
+/// target right away.
+async fn http_tunnel(mut stream: TcpStream, proxy: &Proxy, host: &str, port: u16) -> Result<TcpStream> {
+    let authority = join_host_port(host, port);
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some((username, password)) = &proxy.credentials {
+        let token = general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        request += &format!("Proxy-Authorization: Basic {}\r\n", token);
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    // byte by byte, so that nothing sent by the target right after the head is lost
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LEN {
+            bail!("response head of http proxy is longer than {} bytes", MAX_HEAD_LEN);
+        }
+        head.push(stream.read_u8().await.context("read response of http proxy")?);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(stream),
+        _ => bail!("http proxy refused CONNECT to {}: `{}`", authority, status_line),
+    }
+}
+
+/// Open a UDP association through the SOCKS5 proxy `proxy`, which lasts as long
+/// as the returned socket.
+pub async fn socks5_udp_associate(proxy: &Proxy) -> Result<Socks5Datagram<TcpStream>> {
+    let stream = TcpStream::connect(proxy.addr())
+        .await
+        .context("connect to socks5 proxy")?;
+    let proxy_ip = stream.peer_addr()?.ip();
+
+    // the relay of the proxy is reached with the same address family as the proxy
+    let bind_addr = match proxy_ip {
+        IpAddr::V4(_) => "0.0.0.0:0",
+        IpAddr::V6(_) => "[::]:0",
+    };
+    let datagram = match &proxy.credentials {
+        Some((username, password)) => Socks5Datagram::bind_with_password(stream, bind_addr, username, password).await,
+        None => Socks5Datagram::bind(stream, bind_addr).await,
+    }
+    .context("udp associate through socks5 proxy")?;
+
+    // an unspecified relay address stands for the proxy itself
+    let relay = datagram
+        .proxy_addr()?
+        .to_socket_addrs()?
+        .next()
+        .context("resolve udp relay of socks5 proxy")?;
+    if relay.ip().is_unspecified() {
+        datagram.get_ref().connect(SocketAddr::new(proxy_ip, relay.port())).await?;
+    }
+    Ok(datagram)
+}
+
+/// Ask the SOCKS5 proxy `proxy` to listen for a connection coming from
+/// `host:port`. Also returns the address the remote host has to connect to.
+pub async fn socks5_bind(via: Option<&Proxy>, proxy: &Proxy, host: String, port: u16) -> Result<(Socks5Listener<TcpStream>, SocketAddr)> {
+    let stream = dial(via, proxy).await?;
+    let target_addr = (host.as_str(), port).to_target_addr()?;
+    let listener = Socks5Stream::bind_stream(stream, target_addr, socks5_auth(proxy), client::Config::default())
+        .await
+        .context("bind through socks5 proxy")?;
+
+    // an unspecified address stands for the proxy itself
+    let proxy_ip = match via {
+        Some(_) => lookup_host(proxy.addr())
+            .await?
+            .next()
+            .context("resolve socks5 proxy")?
+            .ip(),
+        None => listener.get_socket_ref().peer_addr()?.ip(),
+    };
+    let bind_addr = match listener.bind_addr() {
+        TargetAddr::Ip(addr) if addr.ip().is_unspecified() => SocketAddr::new(proxy_ip, addr.port()),
+        TargetAddr::Ip(addr) => *addr,
+        TargetAddr::Domain(_, _) => listener
+            .bind_addr()
+            .to_socket_addrs()?
+            .next()
+            .context("resolve bind address of socks5 proxy")?,
+    };
+    Ok((listener, bind_addr))
+}
@@ -1,4 +1,7 @@
 pub mod check;
 pub mod cli;
+pub mod connect;
+pub mod proxy;
+#[allow(clippy::module_inception)]
 pub mod utils;
 // pub mod socks5_server;